@@ -1,13 +1,10 @@
 use anyhow::anyhow;
-use async_std::fs::OpenOptions;
 use async_std::io::prelude::BufReadExt;
 use async_std::io::{self};
 use clap::Parser;
 use futures::executor::block_on;
 use futures::future::FutureExt;
 use futures::stream::StreamExt;
-use futures::{AsyncReadExt, AsyncWriteExt};
-use instant::Duration;
 use libp2p::core::multiaddr::{Multiaddr, Protocol};
 use libp2p::core::transport::OrTransport;
 use libp2p::core::{upgrade, ConnectedPoint};
@@ -56,7 +53,6 @@ impl FromStr for Mode {
 
 const NAMESPACE: &str = "rendezvous";
 const BASE_PATH: &str = "/home/baru/Tmp/libp2p_msg";
-const BUFFER_SIZE: usize = 1024 * 1024;
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "Event", event_process = false)]
@@ -152,7 +148,7 @@ fn main() -> Result<(), Box<dyn Error>> {
             local_key.public(),
         )),
         dcutr: dcutr::behaviour::Behaviour::new(),
-        sendmsg: libp2p_msg::Behaviour::new(),
+        sendmsg: libp2p_msg::Behaviour::new(libp2p_msg::Config::new()),
         rendezvous: rendezvous::client::Behaviour::new(local_key),
 
         has_registered: false,
@@ -243,32 +239,26 @@ fn main() -> Result<(), Box<dyn Error>> {
         .listen_on(opts.relay_address.clone().with(Protocol::P2pCircuit))
         .unwrap();
 
-    let (file_tx, file_rx) = async_std::channel::unbounded();
-
     block_on(async {
         loop {
-            let file_tx = file_tx.clone();
             futures::select! {
-                file_data = file_rx.recv().fuse() => {
-                    match file_data {
-                        Ok((peer_id, data)) => {
-                            swarm.behaviour_mut().sendmsg.send(data, peer_id);
-                        }
-                        Err(e) => eprint!("Error: {:?}", e),
-                    }
-                }
                 line = stdin.select_next_some() => {
                     let line = line.expect("Stdin ont to close");
                     match Command::try_from(line.as_str()) {
                         Ok(Command::ListPeers) => handle_list_peers(&peers).await,
                         Ok(Command::SendFile { peer_id, file_path }) => {
-                            async_std::task::spawn(async move {
-                                if let Err(e) = handle_send_file(peer_id, file_path, file_tx).await {
-                                    eprintln!("Error: {:?}", e);
-                                }
-                            });
+                            match swarm.behaviour_mut().sendmsg.send_file(peer_id, &file_path) {
+                                Ok(id) => println!("Offered {} to {} as transfer {}", file_path.display(), peer_id, id),
+                                Err(e) => eprintln!("Error: {:?}", e),
+                            }
+                        }
+                        Ok(Command::Cancel { id }) => {
+                            let cancelled = swarm.behaviour_mut().sendmsg.cancel_transfer(id);
+                            if !cancelled {
+                                eprintln!("No transfer {}", id);
+                            }
                         }
-                        Err(_) => eprintln!("Wrong command, available commans are: ls, file <PeerId> <File Path>"),
+                        Err(_) => eprintln!("Wrong command, available commans are: ls, file <PeerId> <File Path>, cancel <Transfer ID>"),
                         _ => {}
                     }
                 }
@@ -288,10 +278,70 @@ fn main() -> Result<(), Box<dyn Error>> {
                     SwarmEvent::Behaviour(Event::Dcutr(event)) => {
                         info!("{:?}", event)
                     }
-                    SwarmEvent::Behaviour(Event::Send(event)) => {
-                        if let Err(e) = handle_rev_file(event).await {
-                            eprintln!("Error: {:?}", e);
+                    SwarmEvent::Behaviour(Event::Send(libp2p_msg::Event::File(
+                        libp2p_msg::file::Event::TransferCompleted {
+                            id,
+                            peer,
+                            path,
+                            verified,
+                        },
+                    ))) => {
+                        println!(
+                            "Transfer {} with {} completed: {} (verified: {})",
+                            id,
+                            peer,
+                            path.display(),
+                            verified
+                        );
+                    }
+                    SwarmEvent::Behaviour(Event::Send(libp2p_msg::Event::File(
+                        libp2p_msg::file::Event::IncomingTransfer {
+                            id,
+                            peer,
+                            manifest,
+                            responder,
+                        },
+                    ))) => match manifest.file_name() {
+                        Some(name) => {
+                            let path = PathBuf::from(BASE_PATH).join(name);
+                            println!(
+                                "Receiving {} ({} bytes) from {} as transfer {}",
+                                path.display(),
+                                manifest.size,
+                                peer,
+                                id
+                            );
+                            responder.accept(path);
                         }
+                        None => responder.reject(),
+                    },
+                    SwarmEvent::Behaviour(Event::Send(libp2p_msg::Event::File(
+                        libp2p_msg::file::Event::TransferProgress {
+                            id,
+                            bytes_done,
+                            total,
+                            rate,
+                            ..
+                        },
+                    ))) => {
+                        println!(
+                            "Transfer {}: {}/{} bytes, {:.0} KiB/s",
+                            id,
+                            bytes_done,
+                            total,
+                            rate / 1024.0
+                        );
+                    }
+                    SwarmEvent::Behaviour(Event::Send(libp2p_msg::Event::File(
+                        libp2p_msg::file::Event::TransferFailed { id, peer, reason },
+                    ))) => {
+                        eprintln!("Transfer {} with {} failed: {}", id, peer, reason);
+                    }
+                    SwarmEvent::Behaviour(Event::Send(libp2p_msg::Event::SendFailed { id, peer, error })) => {
+                        eprintln!("Failed to send message {} to {}: {}", id, peer, error);
+                    }
+                    SwarmEvent::Behaviour(Event::Send(event)) => {
+                        info!("{:?}", event)
                     }
                     SwarmEvent::Behaviour(Event::Identify(event)) => {
                         info!("{:?}", event)
@@ -391,6 +441,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 enum Command {
     ListPeers,
     SendFile { peer_id: PeerId, file_path: PathBuf },
+    Cancel { id: libp2p_msg::file::TransferId },
     Unknown,
 }
 
@@ -401,9 +452,9 @@ impl<'a> TryFrom<&'a str> for Command {
         let mut tokens = line.splitn(3, ' ');
         match tokens.next() {
             // 解析 ls 命令
-            Some(token) if token == "ls" => Ok(Command::ListPeers),
+            Some("ls") => Ok(Command::ListPeers),
             // 解析发送文件命令
-            Some(token) if token == "file" => {
+            Some("file") => {
                 let (peer_id, file_path) = {
                     match (tokens.next(), tokens.next()) {
                         (Some(peer_id), Some(file_path)) => (peer_id, file_path),
@@ -419,6 +470,14 @@ impl<'a> TryFrom<&'a str> for Command {
 
                 Ok(Command::SendFile { peer_id, file_path })
             }
+            Some("cancel") => {
+                let id = tokens
+                    .next()
+                    .ok_or_else(|| anyhow!("Missing transfer ID"))?
+                    .parse()
+                    .map_err(|_| anyhow!("Failed to parse transfer ID from &str"))?;
+                Ok(Command::Cancel { id })
+            }
             _ => Ok(Command::Unknown),
         }
     }
@@ -429,37 +488,3 @@ async fn handle_list_peers(peers: &BTreeMap<PeerId, HashSet<ConnectedPoint>>) {
         println!("peer: {}", peer);
     });
 }
-
-async fn handle_send_file(
-    peer_id: PeerId,
-    file_path: PathBuf,
-    file_tx: async_std::channel::Sender<(PeerId, Vec<u8>)>,
-) -> anyhow::Result<()> {
-    let mut file = OpenOptions::new().read(true).open(file_path).await?;
-    loop {
-        let mut buf = vec![0; BUFFER_SIZE];
-        let n = file.read(&mut buf).await?;
-        if n == 0 {
-            break;
-        }
-        // 当读取到的数据没有充满缓冲区时，截断掉没有存放数据的部分
-        buf.truncate(n);
-        file_tx.send((peer_id, buf)).await?;
-        // 加入延迟，以免服务器因读取文件抢占了太多资源，影响其他任务工作
-        async_std::task::sleep(Duration::from_millis(15)).await;
-    }
-    Ok(())
-}
-
-async fn handle_rev_file(event: libp2p_msg::Event) -> anyhow::Result<()> {
-    let target_file_path = format!("{}/{}", BASE_PATH, event.peer);
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&target_file_path)
-        .await?;
-
-    file.write_all(&event.result.data).await?;
-
-    Ok(())
-}
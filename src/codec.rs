@@ -0,0 +1,125 @@
+use std::convert::Infallible;
+use std::{error, fmt};
+
+#[cfg(any(feature = "json", feature = "cbor", feature = "bincode"))]
+use serde::{de::DeserializeOwned, Serialize};
+#[cfg(any(feature = "json", feature = "cbor", feature = "bincode"))]
+use std::marker::PhantomData;
+
+/// Converts the messages of a [`Behaviour`](crate::Behaviour) to and from the
+/// bytes sent on the wire.
+///
+/// The same codec is used for messages, requests and responses.
+pub trait Codec: Send + 'static {
+    /// The type of the messages sent and received.
+    type Message: fmt::Debug + Send + 'static;
+    /// The error returned when a message can't be encoded or decoded.
+    type Error: error::Error + Send + Sync + 'static;
+
+    /// Encodes a message into the bytes to send.
+    fn encode(&self, message: &Self::Message) -> Result<Vec<u8>, Self::Error>;
+
+    /// Decodes received bytes into a message.
+    fn decode(&self, bytes: Vec<u8>) -> Result<Self::Message, Self::Error>;
+}
+
+/// Passes the bytes through unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawCodec;
+
+impl Codec for RawCodec {
+    type Message = Vec<u8>;
+    type Error = Infallible;
+
+    fn encode(&self, message: &Vec<u8>) -> Result<Vec<u8>, Infallible> {
+        Ok(message.clone())
+    }
+
+    fn decode(&self, bytes: Vec<u8>) -> Result<Vec<u8>, Infallible> {
+        Ok(bytes)
+    }
+}
+
+/// Encodes messages as JSON.
+#[cfg(feature = "json")]
+pub struct JsonCodec<T>(PhantomData<fn() -> T>);
+
+#[cfg(feature = "json")]
+impl<T> Default for JsonCodec<T> {
+    fn default() -> Self {
+        JsonCodec(PhantomData)
+    }
+}
+
+#[cfg(feature = "json")]
+impl<T> Codec for JsonCodec<T>
+where
+    T: Serialize + DeserializeOwned + fmt::Debug + Send + 'static,
+{
+    type Message = T;
+    type Error = serde_json::Error;
+
+    fn encode(&self, message: &T) -> Result<Vec<u8>, Self::Error> {
+        serde_json::to_vec(message)
+    }
+
+    fn decode(&self, bytes: Vec<u8>) -> Result<T, Self::Error> {
+        serde_json::from_slice(&bytes)
+    }
+}
+
+/// Encodes messages as CBOR.
+#[cfg(feature = "cbor")]
+pub struct CborCodec<T>(PhantomData<fn() -> T>);
+
+#[cfg(feature = "cbor")]
+impl<T> Default for CborCodec<T> {
+    fn default() -> Self {
+        CborCodec(PhantomData)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl<T> Codec for CborCodec<T>
+where
+    T: Serialize + DeserializeOwned + fmt::Debug + Send + 'static,
+{
+    type Message = T;
+    type Error = serde_cbor::Error;
+
+    fn encode(&self, message: &T) -> Result<Vec<u8>, Self::Error> {
+        serde_cbor::to_vec(message)
+    }
+
+    fn decode(&self, bytes: Vec<u8>) -> Result<T, Self::Error> {
+        serde_cbor::from_slice(&bytes)
+    }
+}
+
+/// Encodes messages with bincode.
+#[cfg(feature = "bincode")]
+pub struct BincodeCodec<T>(PhantomData<fn() -> T>);
+
+#[cfg(feature = "bincode")]
+impl<T> Default for BincodeCodec<T> {
+    fn default() -> Self {
+        BincodeCodec(PhantomData)
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<T> Codec for BincodeCodec<T>
+where
+    T: Serialize + DeserializeOwned + fmt::Debug + Send + 'static,
+{
+    type Message = T;
+    type Error = bincode::Error;
+
+    fn encode(&self, message: &T) -> Result<Vec<u8>, Self::Error> {
+        bincode::serialize(message)
+    }
+
+    fn decode(&self, bytes: Vec<u8>) -> Result<T, Self::Error> {
+        bincode::deserialize(&bytes)
+    }
+}
@@ -0,0 +1,155 @@
+use std::{fmt, io};
+
+/// Algorithms for compressing message payloads, see
+/// [`Config::with_compression`](crate::Config::with_compression).
+///
+/// Each algorithm is behind the cargo feature of the same name. Every
+/// algorithm compiled in is accepted inbound, regardless of the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Compression {
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "deflate")]
+    Deflate,
+}
+
+impl Compression {
+    /// The algorithms compiled in.
+    pub(crate) const ALL: &'static [Compression] = &[
+        #[cfg(feature = "zstd")]
+        Compression::Zstd,
+        #[cfg(feature = "deflate")]
+        Compression::Deflate,
+    ];
+
+    /// The algorithm as it appears in protocol names.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => "zstd",
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => "deflate",
+        }
+    }
+
+    /// The algorithm as it is marked in envelope flags, never 0.
+    pub(crate) fn code(&self) -> u8 {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => 1,
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => 2,
+        }
+    }
+
+    pub(crate) fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.iter().copied().find(|c| c.code() == code)
+    }
+
+    #[cfg_attr(
+        not(any(feature = "zstd", feature = "deflate")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => zstd::bulk::compress(data, 0),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => {
+                use std::io::Write;
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decompresses `data`, failing if the result exceeds `max_size` bytes.
+    #[cfg_attr(
+        not(any(feature = "zstd", feature = "deflate")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn decompress(&self, data: &[u8], max_size: usize) -> io::Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => read_limited(zstd::stream::read::Decoder::new(data)?, max_size),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => read_limited(flate2::read::DeflateDecoder::new(data), max_size),
+        }
+    }
+}
+
+/// Reads `decoder` to the end, failing once it yields more than `max_size`
+/// bytes, so the buffer only grows with the actual output.
+#[cfg(any(feature = "zstd", feature = "deflate"))]
+fn read_limited(decoder: impl io::Read, max_size: usize) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut decoded = Vec::new();
+    decoder
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut decoded)?;
+    if decoded.len() > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decompressed message is too large",
+        ));
+    }
+    Ok(decoded)
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(all(test, any(feature = "zstd", feature = "deflate")))]
+mod tests {
+    use super::*;
+
+    fn data() -> Vec<u8> {
+        b"a fairly compressible payload, ".repeat(64)
+    }
+
+    fn round_trip(compression: Compression) {
+        let data = data();
+        let compressed = compression.compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(
+            compression.decompress(&compressed, data.len()).unwrap(),
+            data
+        );
+    }
+
+    fn rejects_oversized(compression: Compression) {
+        let data = data();
+        let compressed = compression.compress(&data).unwrap();
+        assert!(compression.decompress(&compressed, data.len() - 1).is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        round_trip(Compression::Zstd);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_rejects_oversized() {
+        rejects_oversized(Compression::Zstd);
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn deflate_round_trip() {
+        round_trip(Compression::Deflate);
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn deflate_rejects_oversized() {
+        rejects_oversized(Compression::Deflate);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::handler::StreamSender;
+use crate::{MessageId, Metadata, RawStream, SendError};
+use futures::channel::{mpsc, oneshot};
+use libp2p::core::PeerId;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::{error, fmt};
+
+/// What a [`Control`] asks the behaviour to do.
+pub(crate) enum Command<M> {
+    /// Send a message whose buffer slot is already reserved.
+    Send(MessageId, PeerId, M, Metadata),
+    OpenStream(PeerId, StreamSender),
+}
+
+/// Handle for sending messages from other tasks, see
+/// [`Behaviour::control`](crate::Behaviour::control).
+///
+/// Every message occupies a slot in the buffer of its peer until its
+/// [`Event::SendSucceeded`](crate::Event::SendSucceeded) or
+/// [`Event::SendFailed`](crate::Event::SendFailed) is emitted, so producers
+/// are throttled to the pace at which the peer accepts messages.
+pub struct Control<M = Vec<u8>> {
+    commands: mpsc::UnboundedSender<Command<M>>,
+    buffers: Arc<Mutex<Buffers>>,
+    message_ids: Arc<AtomicU64>,
+}
+
+impl<M> Clone for Control<M> {
+    fn clone(&self) -> Self {
+        Control {
+            commands: self.commands.clone(),
+            buffers: self.buffers.clone(),
+            message_ids: self.message_ids.clone(),
+        }
+    }
+}
+
+impl<M> Control<M> {
+    pub(crate) fn new(
+        commands: mpsc::UnboundedSender<Command<M>>,
+        buffers: Arc<Mutex<Buffers>>,
+        message_ids: Arc<AtomicU64>,
+    ) -> Self {
+        Control {
+            commands,
+            buffers,
+            message_ids,
+        }
+    }
+
+    /// Sends `message` to `peer_id`, waiting until the peer's buffer has room.
+    ///
+    /// The outcome is reported by the behaviour like for
+    /// [`Behaviour::send`](crate::Behaviour::send).
+    pub async fn send(&self, peer_id: PeerId, message: impl Into<M>) -> Result<MessageId, Closed> {
+        self.send_with(peer_id, message, Metadata::default()).await
+    }
+
+    /// Like [`Control::send`], with `metadata` in the message's envelope.
+    pub async fn send_with(
+        &self,
+        peer_id: PeerId,
+        message: impl Into<M>,
+        metadata: Metadata,
+    ) -> Result<MessageId, Closed> {
+        futures::future::poll_fn(|cx| self.lock().poll_reserve(peer_id, cx)).await;
+        self.submit(peer_id, message.into(), metadata)
+            .map_err(|_| Closed)
+    }
+
+    /// Sends `message` to `peer_id` if the peer's buffer has room.
+    pub fn try_send(
+        &self,
+        peer_id: PeerId,
+        message: impl Into<M>,
+    ) -> Result<MessageId, TrySendError<M>> {
+        let message = message.into();
+        if !self.lock().try_reserve(peer_id) {
+            return Err(TrySendError::Full(message));
+        }
+        self.submit(peer_id, message, Metadata::default())
+            .map_err(TrySendError::Closed)
+    }
+
+    /// Opens a raw stream to `peer_id`, see
+    /// [`Behaviour::open_stream`](crate::Behaviour::open_stream).
+    pub async fn open_stream(&self, peer_id: PeerId) -> Result<RawStream, SendError> {
+        let (sender, receiver) = oneshot::channel();
+        self.commands
+            .unbounded_send(Command::OpenStream(peer_id, sender))
+            .map_err(|_| SendError::ConnectionClosed)?;
+        receiver.await.unwrap_or(Err(SendError::ConnectionClosed))
+    }
+
+    fn submit(&self, peer_id: PeerId, message: M, metadata: Metadata) -> Result<MessageId, M> {
+        let id = MessageId(self.message_ids.fetch_add(1, Ordering::Relaxed));
+        self.commands
+            .unbounded_send(Command::Send(id, peer_id, message, metadata))
+            .map_err(|e| {
+                self.lock().release(&peer_id);
+                match e.into_inner() {
+                    Command::Send(_, _, message, _) => message,
+                    Command::OpenStream(..) => unreachable!("sent a message"),
+                }
+            })?;
+        Ok(id)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Buffers> {
+        self.buffers.lock().expect("not poisoned")
+    }
+}
+
+/// The number of unfinished messages per peer, shared by the behaviour and
+/// its [`Control`]s.
+#[derive(Debug)]
+pub(crate) struct Buffers {
+    capacity: usize,
+    used: HashMap<PeerId, usize>,
+    waiting: HashMap<PeerId, Vec<Waker>>,
+}
+
+impl Buffers {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Buffers {
+            capacity,
+            used: HashMap::new(),
+            waiting: HashMap::new(),
+        }
+    }
+
+    /// Takes a slot in the buffer of `peer_id` if there is one.
+    pub(crate) fn try_reserve(&mut self, peer_id: PeerId) -> bool {
+        let used = self.used.get(&peer_id).copied().unwrap_or(0);
+        if used >= self.capacity {
+            return false;
+        }
+        self.used.insert(peer_id, used + 1);
+        true
+    }
+
+    fn poll_reserve(&mut self, peer_id: PeerId, cx: &mut Context<'_>) -> Poll<()> {
+        if self.try_reserve(peer_id) {
+            return Poll::Ready(());
+        }
+        let wakers = self.waiting.entry(peer_id).or_default();
+        if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    /// Frees a slot in the buffer of `peer_id` and wakes the tasks waiting for one.
+    pub(crate) fn release(&mut self, peer_id: &PeerId) {
+        if let Some(used) = self.used.get_mut(peer_id) {
+            *used -= 1;
+            if *used == 0 {
+                self.used.remove(peer_id);
+            }
+        }
+        for waker in self.waiting.remove(peer_id).into_iter().flatten() {
+            waker.wake();
+        }
+    }
+}
+
+/// The behaviour the [`Control`] belongs to was dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "behaviour dropped")
+    }
+}
+
+impl error::Error for Closed {}
+
+/// Why [`Control::try_send`] didn't send a message, which is handed back.
+#[derive(Debug)]
+pub enum TrySendError<M> {
+    /// The peer's buffer is full.
+    Full(M),
+    /// The behaviour was dropped.
+    Closed(M),
+}
+
+impl<M> TrySendError<M> {
+    /// The message that wasn't sent.
+    pub fn into_inner(self) -> M {
+        match self {
+            TrySendError::Full(m) | TrySendError::Closed(m) => m,
+        }
+    }
+}
+
+impl<M> fmt::Display for TrySendError<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "outbound buffer is full"),
+            TrySendError::Closed(_) => write!(f, "behaviour dropped"),
+        }
+    }
+}
+
+impl<M: fmt::Debug> error::Error for TrySendError<M> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    #[test]
+    fn forgets_peers_without_messages() {
+        let peer_id = PeerId::random();
+        let mut buffers = Buffers::new(0);
+        assert!(!buffers.try_reserve(peer_id));
+        assert!(buffers.used.is_empty());
+
+        let mut buffers = Buffers::new(1);
+        assert!(buffers.try_reserve(peer_id));
+        buffers.release(&peer_id);
+        assert!(buffers.used.is_empty());
+    }
+
+    #[test]
+    fn keeps_one_waker_per_task() {
+        let peer_id = PeerId::random();
+        let mut buffers = Buffers::new(1);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(buffers.poll_reserve(peer_id, &mut cx).is_ready());
+        assert!(buffers.poll_reserve(peer_id, &mut cx).is_pending());
+        assert!(buffers.poll_reserve(peer_id, &mut cx).is_pending());
+        assert_eq!(buffers.waiting[&peer_id].len(), 1);
+        buffers.release(&peer_id);
+        assert!(buffers.waiting.is_empty());
+    }
+}
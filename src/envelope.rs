@@ -0,0 +1,481 @@
+use crate::{Compression, MessageId};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use libp2p::core::identity::error::SigningError;
+use libp2p::core::identity::{Keypair, PublicKey};
+use libp2p::core::PeerId;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{error, fmt};
+
+/// Version of the envelope format, the first byte of every envelope.
+const FORMAT: u8 = 1;
+
+const HAS_CONTENT_TYPE: u8 = 0x01;
+const HAS_REPLY_TO: u8 = 0x02;
+/// Bits holding the [`Compression::code`] of the payload, 0 if uncompressed.
+const COMPRESSION_MASK: u8 = 0x0c;
+const COMPRESSION_SHIFT: u8 = 2;
+const HAS_SIGNATURE: u8 = 0x10;
+const SEALED: u8 = 0x20;
+/// The payload is a message of a [`file`](crate::file) transfer.
+const FILE: u8 = 0x40;
+
+/// Prefix of the data covered by a signature, so it can't be mistaken for a
+/// signature made for another purpose.
+const SIGNATURE_DOMAIN: &[u8] = b"libp2p-msg-envelope:";
+
+/// Metadata sent along with a message, see
+/// [`Behaviour::send_with`](crate::Behaviour::send_with).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    content_type: Option<String>,
+    headers: Vec<(String, String)>,
+    reply_to: Option<MessageId>,
+    sealed: bool,
+    file: bool,
+}
+
+impl Metadata {
+    /// Creates empty metadata.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the content type of the payload, e.g. `application/json`.
+    pub fn with_content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    /// Adds an application header. Headers keep their order and a key may
+    /// appear more than once.
+    pub fn with_header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Marks the message as a reply to the message `id` received from the
+    /// same peer.
+    pub fn with_reply_to(mut self, id: MessageId) -> Self {
+        self.reply_to = Some(id);
+        self
+    }
+
+    /// Sets whether the payload is encrypted so that only the recipient can
+    /// read it, even if the message is cached or forwarded by other peers.
+    ///
+    /// The recipient's peer ID must contain its Ed25519 key, and the
+    /// recipient decrypts with the key passed to
+    /// [`Config::with_decryption`](crate::Config::with_decryption). Sealed
+    /// messages are only sent on versions with envelopes.
+    pub fn with_sealed(mut self, b: bool) -> Self {
+        self.sealed = b;
+        self
+    }
+
+    pub(crate) fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    /// Metadata of the messages of a file transfer.
+    pub(crate) fn file() -> Self {
+        Metadata {
+            file: true,
+            ..Metadata::default()
+        }
+    }
+}
+
+/// The envelope a message was received in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    /// The ID the sender assigned to the message.
+    pub id: MessageId,
+    /// When the sender sent the message, by its clock.
+    pub timestamp: SystemTime,
+    pub content_type: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// The ID of the message this one replies to.
+    pub reply_to: Option<MessageId>,
+    /// Whether the payload was encrypted to us, see [`Metadata::with_sealed`].
+    pub sealed: bool,
+    /// Whether the payload belongs to a file transfer.
+    pub(crate) file: bool,
+}
+
+impl Envelope {
+    /// Returns the value of the first header named `key`.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Encodes the envelope of the message `id` sent now, which precedes its
+/// payload on the wire.
+pub fn encode(id: MessageId, metadata: &Metadata) -> Bytes {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut flags = 0;
+    if metadata.content_type.is_some() {
+        flags |= HAS_CONTENT_TYPE;
+    }
+    if metadata.reply_to.is_some() {
+        flags |= HAS_REPLY_TO;
+    }
+    if metadata.sealed {
+        flags |= SEALED;
+    }
+    if metadata.file {
+        flags |= FILE;
+    }
+    let mut buf = BytesMut::new();
+    buf.put_u8(FORMAT);
+    buf.put_u8(flags);
+    put_varint(&mut buf, id.0);
+    put_varint(&mut buf, timestamp);
+    if let Some(content_type) = &metadata.content_type {
+        put_str(&mut buf, content_type);
+    }
+    put_varint(&mut buf, metadata.headers.len() as u64);
+    for (key, value) in &metadata.headers {
+        put_str(&mut buf, key);
+        put_str(&mut buf, value);
+    }
+    if let Some(reply_to) = metadata.reply_to {
+        put_varint(&mut buf, reply_to.0);
+    }
+    buf.freeze()
+}
+
+/// Returns a copy of `envelope` signed with `keypair`, covering the envelope
+/// and the uncompressed `payload`.
+///
+/// The author's public key and the signature are appended to the envelope.
+pub fn sign(envelope: &Bytes, payload: &[u8], keypair: &Keypair) -> Result<Bytes, SigningError> {
+    let mut buf = BytesMut::from(&envelope[..]);
+    buf[1] |= HAS_SIGNATURE;
+    let signature = keypair.sign(&signed_data(&buf, payload))?;
+    put_bytes(&mut buf, &keypair.public().to_protobuf_encoding());
+    put_bytes(&mut buf, &signature);
+    Ok(buf.freeze())
+}
+
+fn signed_data(envelope: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(SIGNATURE_DOMAIN.len() + envelope.len() + payload.len());
+    data.extend_from_slice(SIGNATURE_DOMAIN);
+    data.extend_from_slice(envelope);
+    // The compression is chosen per connection after signing.
+    data[SIGNATURE_DOMAIN.len() + 1] &= !COMPRESSION_MASK;
+    data.extend_from_slice(payload);
+    data
+}
+
+/// Whether the payload following the encoded `envelope` can't be delivered
+/// without it, because it is sealed or belongs to a file transfer.
+pub fn is_required(envelope: &[u8]) -> bool {
+    envelope
+        .get(1)
+        .is_some_and(|flags| flags & (SEALED | FILE) != 0)
+}
+
+/// Returns a copy of `envelope` marking its payload as compressed with `compression`.
+pub fn set_compression(envelope: &Bytes, compression: Compression) -> Bytes {
+    let mut buf = BytesMut::from(&envelope[..]);
+    buf[1] |= compression.code() << COMPRESSION_SHIFT;
+    buf.freeze()
+}
+
+/// A received envelope and its payload.
+#[derive(Debug)]
+pub struct Decoded {
+    pub envelope: Envelope,
+    pub payload: Vec<u8>,
+    /// The author whose signature was verified, if the envelope is signed.
+    pub author: Option<PeerId>,
+}
+
+/// Splits a received frame into its envelope and payload, decompressing the
+/// payload to at most `max_size` bytes if needed, and verifies the signature
+/// if there is one.
+pub fn decode(frame: Vec<u8>, max_size: usize) -> Result<Decoded, DecodeError> {
+    let mut buf = &frame[..];
+    if get_u8(&mut buf)? != FORMAT {
+        return Err(DecodeError::Malformed("unknown envelope format"));
+    }
+    let flags = get_u8(&mut buf)?;
+    let id = MessageId(get_varint(&mut buf)?);
+    let timestamp = UNIX_EPOCH
+        .checked_add(Duration::from_millis(get_varint(&mut buf)?))
+        .ok_or(DecodeError::Malformed("invalid timestamp"))?;
+    let content_type = if flags & HAS_CONTENT_TYPE != 0 {
+        Some(get_str(&mut buf)?)
+    } else {
+        None
+    };
+    let count = get_varint(&mut buf)?;
+    let mut headers = Vec::new();
+    for _ in 0..count {
+        headers.push((get_str(&mut buf)?, get_str(&mut buf)?));
+    }
+    let reply_to = if flags & HAS_REPLY_TO != 0 {
+        Some(MessageId(get_varint(&mut buf)?))
+    } else {
+        None
+    };
+    let signed_len = frame.len() - buf.len();
+    let signature = if flags & HAS_SIGNATURE != 0 {
+        let public_key = PublicKey::from_protobuf_encoding(get_bytes(&mut buf)?)
+            .map_err(|_| DecodeError::Malformed("invalid public key"))?;
+        Some((public_key, get_bytes(&mut buf)?))
+    } else {
+        None
+    };
+    let payload = match (flags & COMPRESSION_MASK) >> COMPRESSION_SHIFT {
+        0 => buf.to_vec(),
+        code => Compression::from_code(code)
+            .ok_or(DecodeError::Malformed("unsupported compression"))?
+            .decompress(buf, max_size)
+            .map_err(|_| DecodeError::Malformed("invalid compressed payload"))?,
+    };
+    let author = match signature {
+        Some((public_key, signature)) => {
+            let author = public_key.to_peer_id();
+            if !public_key.verify(&signed_data(&frame[..signed_len], &payload), signature) {
+                return Err(DecodeError::InvalidSignature(author));
+            }
+            Some(author)
+        }
+        None => None,
+    };
+    let envelope = Envelope {
+        id,
+        timestamp,
+        content_type,
+        headers,
+        reply_to,
+        sealed: flags & SEALED != 0,
+        file: flags & FILE != 0,
+    };
+    Ok(Decoded {
+        envelope,
+        payload,
+        author,
+    })
+}
+
+/// A received envelope is malformed or its signature is invalid.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    Malformed(&'static str),
+    /// The signature doesn't match the public key of `author`.
+    InvalidSignature(PeerId),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Malformed(reason) => write!(f, "invalid envelope: {}", reason),
+            DecodeError::InvalidSignature(author) => {
+                write!(f, "invalid envelope signature of {}", author)
+            }
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+fn put_varint(buf: &mut BytesMut, mut n: u64) {
+    while n >= 0x80 {
+        buf.put_u8(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.put_u8(n as u8);
+}
+
+fn put_str(buf: &mut BytesMut, s: &str) {
+    put_bytes(buf, s.as_bytes());
+}
+
+fn put_bytes(buf: &mut BytesMut, b: &[u8]) {
+    put_varint(buf, b.len() as u64);
+    buf.put_slice(b);
+}
+
+fn get_u8(buf: &mut &[u8]) -> Result<u8, DecodeError> {
+    if !buf.has_remaining() {
+        return Err(DecodeError::Malformed("truncated"));
+    }
+    Ok(buf.get_u8())
+}
+
+fn get_varint(buf: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut n = 0u64;
+    for shift in (0..u64::BITS).step_by(7) {
+        let byte = get_u8(buf)?;
+        if shift == 63 && byte > 1 {
+            break;
+        }
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(DecodeError::Malformed("varint overflow"))
+}
+
+fn get_str(buf: &mut &[u8]) -> Result<String, DecodeError> {
+    let b = get_bytes(buf)?;
+    String::from_utf8(b.to_vec()).map_err(|_| DecodeError::Malformed("invalid UTF-8"))
+}
+
+fn get_bytes<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], DecodeError> {
+    let len = get_varint(buf)? as usize;
+    if buf.remaining() < len {
+        return Err(DecodeError::Malformed("truncated"));
+    }
+    let (b, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> Metadata {
+        Metadata::new()
+            .with_content_type("text/plain")
+            .with_header("key", "value")
+            .with_reply_to(MessageId(7))
+    }
+
+    fn frame(envelope: &[u8], payload: &[u8]) -> Vec<u8> {
+        [envelope, payload].concat()
+    }
+
+    #[test]
+    fn round_trip() {
+        let envelope = encode(MessageId(42), &metadata());
+        let decoded = decode(frame(&envelope, b"payload"), 1024).unwrap();
+        assert_eq!(decoded.envelope.id, MessageId(42));
+        assert_eq!(decoded.envelope.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(decoded.envelope.header("key"), Some("value"));
+        assert_eq!(decoded.envelope.reply_to, Some(MessageId(7)));
+        assert!(!decoded.envelope.sealed);
+        assert_eq!(decoded.payload, b"payload");
+        assert_eq!(decoded.author, None);
+    }
+
+    #[test]
+    fn truncated() {
+        let envelope = encode(MessageId(42), &metadata());
+        for len in 0..envelope.len() {
+            assert!(matches!(
+                decode(envelope[..len].to_vec(), 1024),
+                Err(DecodeError::Malformed(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_format() {
+        let mut frame = frame(&encode(MessageId(42), &metadata()), b"payload");
+        frame[0] = FORMAT + 1;
+        assert_eq!(
+            decode(frame, 1024).unwrap_err(),
+            DecodeError::Malformed("unknown envelope format")
+        );
+    }
+
+    #[test]
+    fn varint_overflow() {
+        let mut buf = BytesMut::new();
+        put_varint(&mut buf, u64::MAX);
+        assert_eq!(get_varint(&mut &buf[..]), Ok(u64::MAX));
+
+        let too_long = [0xff; 11];
+        assert_eq!(
+            get_varint(&mut &too_long[..]),
+            Err(DecodeError::Malformed("varint overflow"))
+        );
+        let mut too_large = [0xff; 10];
+        too_large[9] = 0x02;
+        assert_eq!(
+            get_varint(&mut &too_large[..]),
+            Err(DecodeError::Malformed("varint overflow"))
+        );
+    }
+
+    #[test]
+    fn timestamp_overflow() {
+        let envelope = encode(MessageId(42), &metadata());
+        let mut rest = &envelope[2..];
+        get_varint(&mut rest).unwrap();
+        let mut frame = BytesMut::from(&envelope[..envelope.len() - rest.len()]);
+        get_varint(&mut rest).unwrap();
+        put_varint(&mut frame, u64::MAX);
+        frame.put_slice(rest);
+        // Whether the time is representable depends on the platform.
+        match decode(frame.to_vec(), 1024) {
+            Ok(decoded) => assert_eq!(
+                decoded.envelope.timestamp.duration_since(UNIX_EPOCH).ok(),
+                Some(Duration::from_millis(u64::MAX))
+            ),
+            Err(e) => assert_eq!(e, DecodeError::Malformed("invalid timestamp")),
+        }
+    }
+
+    fn signed(keypair: &Keypair, payload: &[u8]) -> Vec<u8> {
+        let envelope = encode(MessageId(42), &metadata());
+        frame(&sign(&envelope, payload, keypair).unwrap(), payload)
+    }
+
+    #[test]
+    fn signature_verifies() {
+        let keypair = Keypair::generate_ed25519();
+        let decoded = decode(signed(&keypair, b"payload"), 1024).unwrap();
+        assert_eq!(decoded.author, Some(keypair.public().to_peer_id()));
+        assert_eq!(decoded.payload, b"payload");
+    }
+
+    #[test]
+    fn modified_payload_fails_verification() {
+        let keypair = Keypair::generate_ed25519();
+        let mut frame = signed(&keypair, b"payload");
+        *frame.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            decode(frame, 1024).unwrap_err(),
+            DecodeError::InvalidSignature(keypair.public().to_peer_id())
+        );
+    }
+
+    #[test]
+    fn modified_header_fails_verification() {
+        let keypair = Keypair::generate_ed25519();
+        let mut frame = signed(&keypair, b"payload");
+        let at = frame.windows(5).position(|w| w == b"value").unwrap();
+        frame[at] = b'V';
+        assert_eq!(
+            decode(frame, 1024).unwrap_err(),
+            DecodeError::InvalidSignature(keypair.public().to_peer_id())
+        );
+    }
+
+    #[cfg(any(feature = "zstd", feature = "deflate"))]
+    #[test]
+    fn compression_keeps_signature_valid() {
+        let keypair = Keypair::generate_ed25519();
+        let compression = Compression::ALL[0];
+        let payload = b"a fairly compressible payload, ".repeat(64);
+        let envelope = sign(&encode(MessageId(42), &metadata()), &payload, &keypair).unwrap();
+        let envelope = set_compression(&envelope, compression);
+        let compressed = compression.compress(&payload).unwrap();
+        let decoded = decode(frame(&envelope, &compressed), payload.len()).unwrap();
+        assert_eq!(decoded.author, Some(keypair.public().to_peer_id()));
+        assert_eq!(decoded.payload, payload);
+    }
+}
@@ -0,0 +1,2146 @@
+//! File transfers, see [`Behaviour::send_file`](crate::Behaviour::send_file).
+//!
+//! The sender offers a [`Manifest`] describing the file, which the receiving
+//! application accepts or rejects, see [`Event::IncomingTransfer`] and
+//! [`AutoAccept`]. Once the receiver accepted it, the file is sent in chunks
+//! tagged with the transfer ID and their offset. The receiver writes every chunk at its offset into a
+//! partial file, which is moved to its destination when complete, so
+//! concurrent transfers never mix.
+//!
+//! The manifest carries the SHA-256 of every chunk and of the whole file.
+//! The receiver asks for corrupted chunks again, and checks the whole file
+//! before moving it to its destination.
+//!
+//! A transfer whose messages can't reach the peer, e.g. because the
+//! connection closed, is interrupted rather than failed. The sender offers
+//! it again once connected to the peer, right away if a connection is left,
+//! and the receiver accepts it at the
+//! offset up to which it has written the file. With
+//! [`Config::with_transfer_state_dir`](crate::Config::with_transfer_state_dir)
+//! the progress of both ends is persisted, so transfers also resume after a
+//! restart.
+//!
+//! Hashing, reading, writing and verifying files run on worker threads, so
+//! large files don't stall the swarm.
+//!
+//! Either end reports the progress of a transfer periodically, see
+//! [`Config::with_progress_interval`](crate::Config::with_progress_interval),
+//! and may cancel it with
+//! [`Behaviour::cancel_transfer`](crate::Behaviour::cancel_transfer).
+
+use crate::{Config, MessageId, SendError};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::channel::oneshot;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use futures_timer::Delay;
+use libp2p::core::PeerId;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::num::ParseIntError;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use std::{error, fmt};
+
+/// Size of the chunks files are sent in.
+const CHUNK_SIZE: u32 = 256 * 1024;
+/// How many chunks of a transfer may be unfinished at a time, on either end.
+const WINDOW: usize = 8;
+/// How many chunks are received between persisting the progress.
+const PERSIST_INTERVAL: u64 = 16;
+/// How often a corrupted chunk is asked for again, or a lost message sent
+/// again, before giving up.
+const MAX_RESENDS: u8 = 3;
+
+/// Identifies a file transfer, chosen at random by the sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TransferId(u64);
+
+impl fmt::Display for TransferId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// Parses the hexadecimal form the ID is displayed in.
+impl FromStr for TransferId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u64::from_str_radix(s, 16).map(TransferId)
+    }
+}
+
+/// Describes a file offered to a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// The file name, as chosen by the sender. See [`Manifest::file_name`]
+    /// before using it in a path.
+    pub name: String,
+    /// The size of the file in bytes.
+    pub size: u64,
+    /// The size of every chunk but the last.
+    pub chunk_size: u32,
+    /// SHA-256 of the whole file.
+    pub hash: [u8; 32],
+    /// SHA-256 of every chunk, in order.
+    pub chunk_hashes: Vec<[u8; 32]>,
+}
+
+impl Manifest {
+    /// The number of chunks the file is sent in.
+    pub fn chunks(&self) -> u64 {
+        self.size.div_ceil(u64::from(self.chunk_size))
+    }
+
+    /// The last component of [`Manifest::name`], so the sender can't choose
+    /// a directory, if it has one.
+    pub fn file_name(&self) -> Option<&str> {
+        match Path::new(&self.name).components().next_back()? {
+            Component::Normal(name) => name.to_str(),
+            _ => None,
+        }
+    }
+
+    /// The length of the chunk at `offset`.
+    fn chunk_len(&self, offset: u64) -> usize {
+        (self.size - offset).min(u64::from(self.chunk_size)) as usize
+    }
+
+    /// Whether a chunk starts at `offset`, or the file ends there.
+    fn is_boundary(&self, offset: u64) -> bool {
+        offset == self.size
+            || (offset < self.size && offset.is_multiple_of(u64::from(self.chunk_size)))
+    }
+
+    /// The hash of the chunk at `offset`, which must be a boundary.
+    fn chunk_hash(&self, offset: u64) -> [u8; 32] {
+        self.chunk_hashes[(offset / u64::from(self.chunk_size)) as usize]
+    }
+}
+
+/// Event of a file transfer.
+#[derive(Debug)]
+pub enum Event {
+    /// `peer` offers a file, which is only sent once `responder` accepted
+    /// it. Offers accepted by the [`AutoAccept`] policy aren't reported.
+    IncomingTransfer {
+        id: TransferId,
+        peer: PeerId,
+        manifest: Manifest,
+        responder: Responder,
+    },
+    /// The file was sent to, or received from, `peer`.
+    TransferCompleted {
+        id: TransferId,
+        peer: PeerId,
+        /// The file that was sent, or where the received file was written.
+        path: PathBuf,
+        /// Whether the receiver checked the file against the hash of the
+        /// manifest. Transfers of files that don't match it fail with
+        /// [`FailureReason::HashMismatch`] on both ends instead.
+        verified: bool,
+    },
+    /// Data of the transfer was sent or received since the last progress
+    /// event.
+    TransferProgress {
+        id: TransferId,
+        peer: PeerId,
+        /// The bytes whose chunks were sent to the peer so far, or received
+        /// from it.
+        bytes_done: u64,
+        /// The size of the file.
+        total: u64,
+        /// Bytes per second since the last progress event.
+        rate: f64,
+    },
+    /// The transfer was aborted and any partial file removed.
+    TransferFailed {
+        id: TransferId,
+        peer: PeerId,
+        reason: FailureReason,
+    },
+}
+
+/// Why a file transfer failed.
+#[derive(Debug)]
+pub enum FailureReason {
+    /// Reading or writing the file failed.
+    Io(io::Error),
+    /// A message of the transfer couldn't be sent for a reason resuming
+    /// wouldn't fix, or failed too often in a row.
+    Send(SendError),
+    /// The peer aborted the transfer because it failed on its end.
+    Aborted,
+    /// The peer rejected the offer.
+    Rejected,
+    /// The peer sent a message that doesn't match the manifest.
+    InvalidMessage,
+    /// A chunk was still corrupted after asking for it again, or the whole
+    /// file doesn't match the hash of the manifest, on either end.
+    HashMismatch,
+    /// We cancelled the transfer.
+    Cancelled,
+    /// The peer cancelled the transfer.
+    CancelledByPeer,
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureReason::Io(e) => write!(f, "I/O error: {}", e),
+            FailureReason::Send(e) => write!(f, "failed to send: {}", e),
+            FailureReason::Aborted => write!(f, "aborted by peer"),
+            FailureReason::Rejected => write!(f, "rejected by peer"),
+            FailureReason::InvalidMessage => write!(f, "invalid message"),
+            FailureReason::HashMismatch => write!(f, "hash mismatch"),
+            FailureReason::Cancelled => write!(f, "cancelled"),
+            FailureReason::CancelledByPeer => write!(f, "cancelled by peer"),
+        }
+    }
+}
+
+impl error::Error for FailureReason {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            FailureReason::Io(e) => Some(e),
+            FailureReason::Send(e) => Some(e),
+            FailureReason::Aborted
+            | FailureReason::Rejected
+            | FailureReason::InvalidMessage
+            | FailureReason::HashMismatch
+            | FailureReason::Cancelled
+            | FailureReason::CancelledByPeer => None,
+        }
+    }
+}
+
+/// Answers an offer, see [`Event::IncomingTransfer`].
+///
+/// Dropping the responder rejects the offer.
+#[derive(Debug)]
+pub struct Responder {
+    sender: oneshot::Sender<Option<PathBuf>>,
+}
+
+impl Responder {
+    /// Accepts the offer, writing the file to `path` once complete.
+    ///
+    /// The file is written to a partial file next to `path` until then.
+    pub fn accept(self, path: impl Into<PathBuf>) {
+        let _ = self.sender.send(Some(path.into()));
+    }
+
+    /// Rejects the offer, which the sender reports as [`FailureReason::Rejected`].
+    pub fn reject(self) {
+        let _ = self.sender.send(None);
+    }
+}
+
+/// Which offers are accepted without reporting them, see
+/// [`Config::with_auto_accept`](crate::Config::with_auto_accept).
+#[derive(Debug, Clone)]
+pub struct AutoAccept {
+    dir: PathBuf,
+    peers: Option<HashSet<PeerId>>,
+    max_size: Option<u64>,
+}
+
+impl AutoAccept {
+    /// Accepts every offer, writing the file to `dir` under its
+    /// [`Manifest::file_name`].
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        AutoAccept {
+            dir: dir.into(),
+            peers: None,
+            max_size: None,
+        }
+    }
+
+    /// Only accepts offers from `peers`.
+    pub fn with_peers(mut self, peers: impl IntoIterator<Item = PeerId>) -> Self {
+        self.peers = Some(peers.into_iter().collect());
+        self
+    }
+
+    /// Only accepts files of at most `size` bytes.
+    pub fn with_max_size(mut self, size: u64) -> Self {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// Where the file offered by `peer` goes, if the offer is accepted.
+    fn destination(&self, peer: &PeerId, manifest: &Manifest) -> Option<PathBuf> {
+        if self.peers.as_ref().is_some_and(|p| !p.contains(peer))
+            || self.max_size.is_some_and(|max| manifest.size > max)
+        {
+            return None;
+        }
+        Some(self.dir.join(manifest.file_name()?))
+    }
+}
+
+const OFFER: u8 = 1;
+const ACCEPT: u8 = 2;
+const CHUNK: u8 = 3;
+const COMPLETE: u8 = 4;
+const ABORT: u8 = 5;
+const RESEND: u8 = 6;
+const CANCEL: u8 = 7;
+const REJECT: u8 = 8;
+/// Codes of why an [`Message::Abort`] was sent.
+const ABORT_FAILED: u8 = 0;
+const ABORT_HASH_MISMATCH: u8 = 1;
+/// Marks sent messages of an interrupted transfer, whose outcome no longer
+/// matters. Never on the wire.
+const STALE: u8 = 0;
+
+/// A message of the transfer protocol, sent in the payload of a message
+/// whose envelope is marked as belonging to a file transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Message {
+    /// Sender to receiver: the file to transfer, or to resume.
+    Offer(TransferId, Manifest),
+    /// Receiver to sender: send the chunks from an offset on.
+    Accept(TransferId, u64),
+    /// Sender to receiver: the data at an offset.
+    Chunk(TransferId, u64, Bytes),
+    /// Receiver to sender: the file was written to its destination, with
+    /// the hash it was verified against.
+    Complete(TransferId, [u8; 32]),
+    /// Either way: the transfer failed on the sending peer, with the
+    /// `ABORT_*` code of why.
+    Abort(TransferId, u8),
+    /// Receiver to sender: the chunk at an offset arrived corrupted.
+    Resend(TransferId, u64),
+    /// Either way: the application cancelled the transfer.
+    Cancel(TransferId),
+    /// Receiver to sender: the application rejected the offer.
+    Reject(TransferId),
+}
+
+impl Message {
+    fn transfer(&self) -> TransferId {
+        match self {
+            Message::Offer(id, _)
+            | Message::Accept(id, _)
+            | Message::Chunk(id, ..)
+            | Message::Complete(id, _)
+            | Message::Abort(id, _)
+            | Message::Resend(id, _)
+            | Message::Cancel(id)
+            | Message::Reject(id) => *id,
+        }
+    }
+
+    /// Whether the message ends the transfer, so it isn't worth dialing the
+    /// peer for.
+    pub(crate) fn ends_transfer(&self) -> bool {
+        matches!(self, Message::Abort(..) | Message::Cancel(_))
+    }
+
+    /// Tells the peer that the transfer `id` failed for `reason`.
+    fn abort(id: TransferId, reason: &FailureReason) -> Self {
+        match reason {
+            FailureReason::HashMismatch => Message::Abort(id, ABORT_HASH_MISMATCH),
+            _ => Message::Abort(id, ABORT_FAILED),
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            Message::Offer(..) => OFFER,
+            Message::Accept(..) => ACCEPT,
+            Message::Chunk(..) => CHUNK,
+            Message::Complete(..) => COMPLETE,
+            Message::Abort(..) => ABORT,
+            Message::Resend(..) => RESEND,
+            Message::Cancel(_) => CANCEL,
+            Message::Reject(_) => REJECT,
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u8(self.tag());
+        buf.put_u64(self.transfer().0);
+        match self {
+            Message::Offer(_, manifest) => put_manifest(&mut buf, manifest),
+            Message::Accept(_, offset) | Message::Resend(_, offset) => buf.put_u64(*offset),
+            Message::Complete(_, hash) => buf.put_slice(hash),
+            Message::Chunk(_, offset, data) => {
+                buf.put_u64(*offset);
+                buf.put_slice(data);
+            }
+            Message::Abort(_, code) => buf.put_u8(*code),
+            Message::Cancel(_) | Message::Reject(_) => {}
+        }
+        buf.freeze()
+    }
+
+    fn decode(mut buf: &[u8]) -> Option<Self> {
+        if buf.remaining() < 9 {
+            return None;
+        }
+        let tag = buf.get_u8();
+        let id = TransferId(buf.get_u64());
+        let message = match tag {
+            OFFER => {
+                let manifest = get_manifest(&mut buf)?;
+                if !buf.is_empty() {
+                    return None;
+                }
+                Message::Offer(id, manifest)
+            }
+            CHUNK => {
+                if buf.remaining() < 8 {
+                    return None;
+                }
+                let offset = buf.get_u64();
+                Message::Chunk(id, offset, Bytes::copy_from_slice(buf))
+            }
+            ACCEPT if buf.remaining() == 8 => Message::Accept(id, buf.get_u64()),
+            COMPLETE if buf.remaining() == 32 => {
+                let mut hash = [0; 32];
+                buf.copy_to_slice(&mut hash);
+                Message::Complete(id, hash)
+            }
+            ABORT if buf.remaining() == 1 => Message::Abort(id, buf.get_u8()),
+            RESEND if buf.remaining() == 8 => Message::Resend(id, buf.get_u64()),
+            CANCEL if buf.is_empty() => Message::Cancel(id),
+            REJECT if buf.is_empty() => Message::Reject(id),
+            _ => return None,
+        };
+        Some(message)
+    }
+}
+
+fn put_str(buf: &mut BytesMut, s: &str) {
+    buf.put_u16(s.len() as u16);
+    buf.put_slice(s.as_bytes());
+}
+
+fn get_str(buf: &mut &[u8]) -> Option<String> {
+    if buf.remaining() < 2 {
+        return None;
+    }
+    let len = buf.get_u16() as usize;
+    if buf.remaining() < len {
+        return None;
+    }
+    let s = String::from_utf8(buf[..len].to_vec()).ok()?;
+    buf.advance(len);
+    Some(s)
+}
+
+fn put_manifest(buf: &mut BytesMut, manifest: &Manifest) {
+    put_str(buf, &manifest.name);
+    buf.put_u64(manifest.size);
+    buf.put_u32(manifest.chunk_size);
+    buf.put_slice(&manifest.hash);
+    for hash in &manifest.chunk_hashes {
+        buf.put_slice(hash);
+    }
+}
+
+fn get_manifest(buf: &mut &[u8]) -> Option<Manifest> {
+    let name = get_str(buf)?;
+    if buf.remaining() < 8 + 4 + 32 {
+        return None;
+    }
+    let size = buf.get_u64();
+    let chunk_size = buf.get_u32();
+    let mut hash = [0; 32];
+    buf.copy_to_slice(&mut hash);
+    if chunk_size == 0 {
+        return None;
+    }
+    let chunks = size.div_ceil(u64::from(chunk_size));
+    if (buf.remaining() as u64) / 32 < chunks {
+        return None;
+    }
+    let chunk_hashes = (0..chunks)
+        .map(|_| {
+            let mut hash = [0; 32];
+            buf.copy_to_slice(&mut hash);
+            hash
+        })
+        .collect();
+    Some(Manifest {
+        name,
+        size,
+        chunk_size,
+        hash,
+        chunk_hashes,
+    })
+}
+
+/// The persisted state of a transfer.
+struct Record {
+    peer: PeerId,
+    id: TransferId,
+    manifest: Manifest,
+    /// The file that is sent, or where the received file goes.
+    path: PathBuf,
+    /// Up to where the received file was written, 0 for sent files.
+    offset: u64,
+}
+
+impl Record {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        let peer = self.peer.to_bytes();
+        buf.put_u16(peer.len() as u16);
+        buf.put_slice(&peer);
+        buf.put_u64(self.id.0);
+        put_manifest(&mut buf, &self.manifest);
+        put_str(&mut buf, &self.path.to_string_lossy());
+        buf.put_u64(self.offset);
+        buf.to_vec()
+    }
+
+    fn decode(mut buf: &[u8]) -> Option<Self> {
+        if buf.remaining() < 2 {
+            return None;
+        }
+        let len = buf.get_u16() as usize;
+        if buf.remaining() < len + 8 {
+            return None;
+        }
+        let peer = PeerId::from_bytes(&buf[..len]).ok()?;
+        buf.advance(len);
+        let id = TransferId(buf.get_u64());
+        let manifest = get_manifest(&mut buf)?;
+        let path = PathBuf::from(get_str(&mut buf)?);
+        if buf.remaining() != 8 {
+            return None;
+        }
+        let offset = buf.get_u64();
+        if !manifest.is_boundary(offset) {
+            return None;
+        }
+        Some(Record {
+            peer,
+            id,
+            manifest,
+            path,
+            offset,
+        })
+    }
+
+    /// Writes the record to `dir` as `name`, replacing any previous one.
+    fn save(&self, dir: &Path, name: &str) -> io::Result<()> {
+        let tmp = dir.join(format!("{}.tmp", name));
+        fs::write(&tmp, self.encode())?;
+        fs::rename(tmp, dir.join(name))
+    }
+}
+
+fn outgoing_record(id: TransferId) -> String {
+    format!("{}.out", id)
+}
+
+fn incoming_record((peer, id): (PeerId, TransferId)) -> String {
+    format!("{}-{}.in", peer, id)
+}
+
+/// Reads `file` to compute the manifest it is offered with as `name`.
+fn manifest(file: &mut File, name: String) -> io::Result<Manifest> {
+    let mut hasher = Sha256::new();
+    let mut chunk_hashes = Vec::new();
+    let mut size = 0;
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
+    loop {
+        chunk.clear();
+        file.take(u64::from(CHUNK_SIZE)).read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            break;
+        }
+        hasher.update(&chunk);
+        chunk_hashes.push(Sha256::digest(&chunk).into());
+        size += chunk.len() as u64;
+    }
+    Ok(Manifest {
+        name,
+        size,
+        chunk_size: CHUNK_SIZE,
+        hash: hasher.finalize().into(),
+        chunk_hashes,
+    })
+}
+
+/// Reads the `len` bytes at `offset` from `file`.
+fn read_chunk(file: &mut File, offset: u64, len: usize) -> io::Result<Bytes> {
+    let mut data = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data.into())
+}
+
+/// Writes `data` at `offset` to `file` if it hashes to `hash`, returning
+/// whether it did.
+fn write_chunk(file: &mut File, offset: u64, data: &[u8], hash: [u8; 32]) -> io::Result<bool> {
+    if <[u8; 32]>::from(Sha256::digest(data)) != hash {
+        return Ok(false);
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)?;
+    Ok(true)
+}
+
+/// SHA-256 of the file at `path`.
+fn hash_file(path: &Path) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+/// Where a file received to `path` is written until it is complete.
+fn partial_path(path: &Path, id: TransferId) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{}.part", name, id))
+}
+
+/// The progress last reported of a transfer.
+struct Progress {
+    bytes: u64,
+    at: Instant,
+}
+
+impl Progress {
+    fn new(bytes: u64) -> Self {
+        Progress {
+            bytes,
+            at: Instant::now(),
+        }
+    }
+
+    /// Records `bytes` as reported at `now` if they changed, returning the
+    /// rate since the last report.
+    fn report(&mut self, bytes: u64, now: Instant) -> Option<f64> {
+        if bytes == self.bytes {
+            return None;
+        }
+        let secs = now.duration_since(self.at).as_secs_f64();
+        let rate = if secs > 0.0 {
+            bytes.saturating_sub(self.bytes) as f64 / secs
+        } else {
+            0.0
+        };
+        *self = Progress { bytes, at: now };
+        Some(rate)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendState {
+    /// Waiting for the receiver to accept the offer.
+    Offered,
+    Accepted,
+    /// Waiting for a connection to the receiver to offer the file again.
+    Interrupted,
+}
+
+/// A file being hashed before it is offered.
+struct Hashing {
+    peer: PeerId,
+    path: PathBuf,
+}
+
+/// A file being sent.
+struct Outgoing {
+    peer: PeerId,
+    path: PathBuf,
+    /// `None` while a chunk is read from it.
+    file: Option<File>,
+    manifest: Manifest,
+    state: SendState,
+    /// Offset of the next chunk to send.
+    next_offset: u64,
+    /// Offsets of the chunks the receiver asked for again.
+    resend: BTreeSet<u64>,
+    /// Chunks handed to the behaviour whose outcome is pending.
+    in_flight: usize,
+    /// The chunks up to here were reported sent.
+    acked: u64,
+    /// Offsets of the chunks reported sent beyond `acked`.
+    acked_ahead: BTreeSet<u64>,
+    /// How often the transfer was resumed on a remaining connection since a
+    /// chunk last got through.
+    resumes: u8,
+    progress: Progress,
+}
+
+/// An offer waiting for the application.
+struct Offer {
+    manifest: Manifest,
+    /// The destination, none if rejected.
+    answer: oneshot::Receiver<Option<PathBuf>>,
+}
+
+/// A file being received.
+struct Incoming {
+    manifest: Manifest,
+    /// `None` while it is created, a chunk is written to it, the progress
+    /// persisted or the complete file verified and moved.
+    file: Option<File>,
+    /// Chunks waiting to be checked and written, in arrival order, at most
+    /// [`WINDOW`].
+    pending: VecDeque<(u64, Bytes)>,
+    /// Whether to persist the progress once the file is free.
+    persist: bool,
+    /// Where the file is moved once complete.
+    path: PathBuf,
+    /// The file is written up to here.
+    next_offset: u64,
+    /// Offsets of the chunks written beyond `next_offset`.
+    ahead: BTreeSet<u64>,
+    /// `next_offset` when the progress was last persisted.
+    persisted: u64,
+    /// How often corrupted chunks were asked for again, by offset.
+    resends: HashMap<u64, u8>,
+    /// How often answers to the sender were lost in a row.
+    failed_replies: u8,
+    progress: Progress,
+}
+
+/// A message handed to the behaviour whose outcome is pending.
+struct Sent {
+    peer: PeerId,
+    transfer: TransferId,
+    tag: u8,
+    /// The offset the message refers to, if any.
+    offset: u64,
+}
+
+/// The outcome of blocking file I/O, run on a worker thread.
+enum Done {
+    /// The manifest of a file to offer was computed, and persisted if needed.
+    Hashed(TransferId, File, io::Result<Manifest>),
+    /// The chunk at an offset of a file being sent was read.
+    Read(TransferId, File, u64, io::Result<Bytes>),
+    /// The chunk at an offset of a file being received was checked against
+    /// the manifest, and written if it matched.
+    Written((PeerId, TransferId), File, u64, io::Result<bool>),
+    /// The progress of a file being received was persisted up to an offset.
+    Persisted((PeerId, TransferId), File, u64, io::Result<()>),
+    /// A file received completely was hashed.
+    Verified((PeerId, TransferId), io::Result<[u8; 32]>),
+    /// The partial file of a file being received was created.
+    Created((PeerId, TransferId), PathBuf, io::Result<File>),
+    /// A file received completely was moved to its destination.
+    Moved((PeerId, TransferId), PathBuf, io::Result<()>),
+    /// A file was removed, if it existed.
+    Removed,
+}
+
+/// Whether a message that failed with `error` may reach the peer later.
+fn is_transient(error: &SendError) -> bool {
+    matches!(
+        error,
+        SendError::NotConnected
+            | SendError::QueueFull
+            | SendError::DialFailed
+            | SendError::Expired
+            | SendError::ConnectionClosed
+            | SendError::Timeout
+            | SendError::Io(_)
+    )
+}
+
+/// Runs `job` on a worker thread.
+fn blocking(job: impl FnOnce() -> Done + Send + 'static) -> BoxFuture<'static, Done> {
+    async_std::task::spawn_blocking(job).boxed()
+}
+
+impl Outgoing {
+    fn bytes_done(&self) -> u64 {
+        let ahead: u64 = self
+            .acked_ahead
+            .iter()
+            .map(|offset| self.manifest.chunk_len(*offset) as u64)
+            .sum();
+        self.acked + ahead
+    }
+}
+
+impl Incoming {
+    fn bytes_done(&self) -> u64 {
+        let ahead: u64 = self
+            .ahead
+            .iter()
+            .map(|offset| self.manifest.chunk_len(*offset) as u64)
+            .sum();
+        self.next_offset + ahead
+    }
+}
+
+/// The file transfers of a behaviour.
+///
+/// The behaviour sends the messages returned by [`Transfers::next_message`],
+/// reports their outcome, and hands over the file messages it receives.
+pub(crate) struct Transfers {
+    auto_accept: Option<AutoAccept>,
+    /// Where the progress of transfers is persisted, if at all.
+    state_dir: Option<PathBuf>,
+    hashing: HashMap<TransferId, Hashing>,
+    outgoing: HashMap<TransferId, Outgoing>,
+    incoming: HashMap<(PeerId, TransferId), Incoming>,
+    /// Offers waiting for the application to accept or reject them.
+    offers: HashMap<(PeerId, TransferId), Offer>,
+    /// Messages handed to the behaviour whose outcome is pending.
+    sent: HashMap<MessageId, Sent>,
+    /// Received files whose completion the sender wasn't told yet, with
+    /// their hash and how often telling it failed.
+    completed: HashMap<(PeerId, TransferId), ([u8; 32], u8)>,
+    /// Messages to send, oldest first.
+    outbox: VecDeque<(PeerId, Message)>,
+    /// Blocking file I/O running on worker threads.
+    io: FuturesUnordered<BoxFuture<'static, Done>>,
+    /// Woken when I/O is started outside of [`Transfers::poll`].
+    waker: Option<Waker>,
+    events: VecDeque<Event>,
+    progress_interval: Duration,
+    progress_timer: Option<Delay>,
+}
+
+impl Transfers {
+    /// Creates the transfers, restoring those persisted in the transfer
+    /// state directory of `config` as interrupted.
+    pub(crate) fn new(config: &Config) -> Self {
+        let mut transfers = Transfers {
+            auto_accept: config.auto_accept().cloned(),
+            state_dir: None,
+            hashing: HashMap::new(),
+            outgoing: HashMap::new(),
+            incoming: HashMap::new(),
+            offers: HashMap::new(),
+            sent: HashMap::new(),
+            completed: HashMap::new(),
+            outbox: VecDeque::new(),
+            io: FuturesUnordered::new(),
+            waker: None,
+            events: VecDeque::new(),
+            progress_interval: config.progress_interval(),
+            progress_timer: None,
+        };
+        if let Some(dir) = config.transfer_state_dir() {
+            if let Err(e) = transfers.load(dir) {
+                log::warn!("Failed to load transfers from {}: {}", dir.display(), e);
+            }
+            transfers.state_dir = Some(dir.to_owned());
+        }
+        transfers
+    }
+
+    /// Restores the transfers persisted in `dir`, dropping the records of
+    /// those whose files are gone.
+    fn load(&mut self, dir: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let outgoing = match path.extension().and_then(|e| e.to_str()) {
+                Some("out") => true,
+                Some("in") => false,
+                _ => continue,
+            };
+            let record = match fs::read(&path).ok().and_then(|b| Record::decode(&b)) {
+                Some(record) => record,
+                None => {
+                    log::debug!("Removing invalid transfer record {}", path.display());
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+            };
+            let restored = if outgoing {
+                File::open(&record.path).map(|file| self.restore_outgoing(record, file))
+            } else {
+                OpenOptions::new()
+                    .write(true)
+                    .open(partial_path(&record.path, record.id))
+                    .map(|file| self.restore_incoming(record, file))
+            };
+            if let Err(e) = restored {
+                log::debug!("Dropping transfer record {}: {}", path.display(), e);
+                let _ = fs::remove_file(&path);
+            }
+        }
+        Ok(())
+    }
+
+    fn restore_outgoing(&mut self, record: Record, file: File) {
+        self.outgoing.insert(
+            record.id,
+            Outgoing {
+                peer: record.peer,
+                path: record.path,
+                file: Some(file),
+                manifest: record.manifest,
+                state: SendState::Interrupted,
+                next_offset: 0,
+                resend: BTreeSet::new(),
+                in_flight: 0,
+                acked: 0,
+                acked_ahead: BTreeSet::new(),
+                resumes: 0,
+                progress: Progress::new(0),
+            },
+        );
+    }
+
+    fn restore_incoming(&mut self, record: Record, file: File) {
+        self.incoming.insert(
+            (record.peer, record.id),
+            Incoming {
+                manifest: record.manifest,
+                file: Some(file),
+                pending: VecDeque::new(),
+                persist: false,
+                path: record.path,
+                next_offset: record.offset,
+                ahead: BTreeSet::new(),
+                persisted: record.offset,
+                resends: HashMap::new(),
+                failed_replies: 0,
+                progress: Progress::new(record.offset),
+            },
+        );
+    }
+
+    /// Offers the file at `path` to `peer` once it is hashed.
+    pub(crate) fn send_file(&mut self, peer: PeerId, path: &Path) -> io::Result<TransferId> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?
+            .to_string_lossy()
+            .into_owned();
+        let mut file = File::open(path)?;
+        let id = TransferId(rand::random());
+        let dir = self.state_dir.clone();
+        let record_path = path.to_owned();
+        self.spawn(blocking(move || {
+            let result = manifest(&mut file, name).and_then(|manifest| {
+                if let Some(dir) = dir {
+                    let record = Record {
+                        peer,
+                        id,
+                        manifest: manifest.clone(),
+                        path: record_path,
+                        offset: 0,
+                    };
+                    record.save(&dir, &outgoing_record(id))?;
+                }
+                Ok(manifest)
+            });
+            Done::Hashed(id, file, result)
+        }));
+        self.hashing.insert(
+            id,
+            Hashing {
+                peer,
+                path: path.to_owned(),
+            },
+        );
+        Ok(id)
+    }
+
+    fn on_hashed(&mut self, id: TransferId, file: File, result: io::Result<Manifest>) {
+        let Hashing { peer, path } = match self.hashing.remove(&id) {
+            Some(hashing) => hashing,
+            None => {
+                // Cancelled while hashing.
+                self.forget(&outgoing_record(id));
+                return;
+            }
+        };
+        let manifest = match result {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                self.forget(&outgoing_record(id));
+                self.events.push_back(Event::TransferFailed {
+                    id,
+                    peer,
+                    reason: FailureReason::Io(e),
+                });
+                return;
+            }
+        };
+        self.outbox
+            .push_back((peer, Message::Offer(id, manifest.clone())));
+        self.outgoing.insert(
+            id,
+            Outgoing {
+                peer,
+                path,
+                file: Some(file),
+                manifest,
+                state: SendState::Offered,
+                next_offset: 0,
+                resend: BTreeSet::new(),
+                in_flight: 0,
+                acked: 0,
+                acked_ahead: BTreeSet::new(),
+                resumes: 0,
+                progress: Progress::new(0),
+            },
+        );
+    }
+
+    /// Offers the transfers to `peer` that weren't accepted yet again, so
+    /// the receiver tells where to resume.
+    pub(crate) fn on_connected(&mut self, peer: PeerId) {
+        for (id, transfer) in &mut self.outgoing {
+            if transfer.peer == peer && transfer.state != SendState::Accepted {
+                transfer.state = SendState::Offered;
+                transfer.resumes = 0;
+                self.outbox
+                    .push_back((peer, Message::Offer(*id, transfer.manifest.clone())));
+            }
+        }
+    }
+
+    /// Interrupts the transfers to `peer`, which is no longer connected, and
+    /// persists the progress of those from it.
+    pub(crate) fn on_disconnected(&mut self, peer: PeerId) {
+        let ids: Vec<_> = self
+            .outgoing
+            .iter()
+            .filter(|(_, t)| t.peer == peer)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            self.interrupt(id);
+        }
+        let keys: Vec<_> = self
+            .incoming
+            .keys()
+            .filter(|(p, _)| *p == peer)
+            .copied()
+            .collect();
+        for key in keys {
+            self.persist_incoming(key);
+        }
+    }
+
+    /// Returns the next message to send, after starting to read the next
+    /// chunks of accepted transfers, which are queued once read.
+    pub(crate) fn next_message(&mut self) -> Option<(PeerId, Message)> {
+        self.read_chunks();
+        self.outbox.pop_front()
+    }
+
+    /// Puts `messages` that couldn't be sent yet back in front of the queue.
+    ///
+    /// Chunks among them still count against the window of their transfer,
+    /// so it doesn't read more until they are sent.
+    pub(crate) fn requeue(&mut self, messages: Vec<(PeerId, Message)>) {
+        for message in messages.into_iter().rev() {
+            self.outbox.push_front(message);
+        }
+    }
+
+    /// Starts reading the next chunk of every accepted transfer whose window
+    /// has room and whose file is free.
+    fn read_chunks(&mut self) {
+        let mut started = false;
+        for (id, transfer) in &mut self.outgoing {
+            if transfer.state != SendState::Accepted
+                || transfer.in_flight >= WINDOW
+                || (transfer.resend.is_empty() && transfer.next_offset >= transfer.manifest.size)
+            {
+                continue;
+            }
+            let mut file = match transfer.file.take() {
+                Some(file) => file,
+                None => continue,
+            };
+            let offset = match transfer.resend.pop_first() {
+                Some(offset) => offset,
+                None => {
+                    let offset = transfer.next_offset;
+                    transfer.next_offset += transfer.manifest.chunk_len(offset) as u64;
+                    offset
+                }
+            };
+            let (id, len) = (*id, transfer.manifest.chunk_len(offset));
+            self.io.push(blocking(move || {
+                let result = read_chunk(&mut file, offset, len);
+                Done::Read(id, file, offset, result)
+            }));
+            started = true;
+        }
+        if started {
+            self.wake();
+        }
+    }
+
+    fn on_read(&mut self, id: TransferId, file: File, offset: u64, result: io::Result<Bytes>) {
+        let transfer = match self.outgoing.get_mut(&id) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        transfer.file = Some(file);
+        match result {
+            Ok(data) if transfer.state == SendState::Accepted => {
+                transfer.in_flight += 1;
+                self.outbox
+                    .push_back((transfer.peer, Message::Chunk(id, offset, data)));
+            }
+            // Interrupted meanwhile, resuming sends the chunk again.
+            Ok(_) => {}
+            Err(e) => self.fail_outgoing(id, FailureReason::Io(e), true),
+        }
+    }
+
+    /// Records that `message` to `peer` was sent as `id`.
+    pub(crate) fn track(&mut self, id: MessageId, peer: PeerId, message: &Message) {
+        let offset = match message {
+            Message::Accept(_, offset)
+            | Message::Chunk(_, offset, _)
+            | Message::Resend(_, offset) => *offset,
+            _ => 0,
+        };
+        let sent = Sent {
+            peer,
+            transfer: message.transfer(),
+            tag: message.tag(),
+            offset,
+        };
+        self.sent.insert(id, sent);
+    }
+
+    /// Whether the message `id` belongs to a transfer.
+    pub(crate) fn owns(&self, id: &MessageId) -> bool {
+        self.sent.contains_key(id)
+    }
+
+    pub(crate) fn on_sent(&mut self, id: &MessageId) {
+        let sent = match self.sent.remove(id) {
+            Some(sent) => sent,
+            None => return,
+        };
+        let key = (sent.peer, sent.transfer);
+        match sent.tag {
+            CHUNK => {
+                if let Some(transfer) = self.outgoing.get_mut(&sent.transfer) {
+                    transfer.in_flight -= 1;
+                    transfer.resumes = 0;
+                    if sent.offset >= transfer.acked {
+                        transfer.acked_ahead.insert(sent.offset);
+                    }
+                    while transfer.acked_ahead.remove(&transfer.acked) {
+                        transfer.acked += transfer.manifest.chunk_len(transfer.acked) as u64;
+                    }
+                }
+            }
+            ACCEPT | RESEND => {
+                if let Some(transfer) = self.incoming.get_mut(&key) {
+                    transfer.failed_replies = 0;
+                }
+            }
+            COMPLETE => {
+                self.completed.remove(&key);
+            }
+            _ => {}
+        }
+    }
+
+    /// Handles the loss of the message `id`, while still `connected` to the
+    /// peer or not.
+    ///
+    /// Transfers whose messages may reach the peer later are interrupted
+    /// and resumed, and lost answers of the receiver sent again, up to
+    /// [`MAX_RESENDS`] times in a row. Others fail.
+    pub(crate) fn on_send_failed(&mut self, id: &MessageId, error: SendError, connected: bool) {
+        let sent = match self.sent.remove(id) {
+            Some(sent) => sent,
+            None => return,
+        };
+        match sent.tag {
+            OFFER | CHUNK if is_transient(&error) => {
+                self.interrupt(sent.transfer);
+                if connected {
+                    self.resume(sent.transfer, error);
+                }
+            }
+            OFFER | CHUNK => self.fail_outgoing(sent.transfer, FailureReason::Send(error), false),
+            ACCEPT | RESEND => self.on_reply_failed(sent, error, connected),
+            COMPLETE => self.on_complete_failed(sent, error, connected),
+            _ => {}
+        }
+    }
+
+    /// Offers the interrupted transfer `id` again on the connections left,
+    /// unless that failed [`MAX_RESENDS`] times in a row.
+    fn resume(&mut self, id: TransferId, error: SendError) {
+        let transfer = match self.outgoing.get_mut(&id) {
+            Some(transfer) if transfer.state == SendState::Interrupted => transfer,
+            _ => return,
+        };
+        if transfer.resumes == MAX_RESENDS {
+            return self.fail_outgoing(id, FailureReason::Send(error), true);
+        }
+        transfer.resumes += 1;
+        transfer.state = SendState::Offered;
+        self.outbox
+            .push_back((transfer.peer, Message::Offer(id, transfer.manifest.clone())));
+    }
+
+    /// Sends a lost answer to an offer or a corrupted chunk again. Once
+    /// disconnected, the sender offers the file again after reconnecting.
+    fn on_reply_failed(&mut self, sent: Sent, error: SendError, connected: bool) {
+        let key = (sent.peer, sent.transfer);
+        let transfer = match self.incoming.get_mut(&key) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        if !is_transient(&error) {
+            return self.fail_incoming(key, FailureReason::Send(error), false);
+        }
+        if !connected {
+            return;
+        }
+        if transfer.failed_replies == MAX_RESENDS {
+            return self.fail_incoming(key, FailureReason::Send(error), true);
+        }
+        transfer.failed_replies += 1;
+        let message = match sent.tag {
+            ACCEPT => Message::Accept(sent.transfer, transfer.next_offset),
+            _ => Message::Resend(sent.transfer, sent.offset),
+        };
+        self.outbox.push_back((sent.peer, message));
+    }
+
+    /// Tells the sender again that the file was received. Once
+    /// disconnected, the sender is told when it offers the file again.
+    fn on_complete_failed(&mut self, sent: Sent, error: SendError, connected: bool) {
+        let key = (sent.peer, sent.transfer);
+        let (hash, attempts) = match self.completed.get_mut(&key) {
+            Some((hash, attempts)) => (*hash, attempts),
+            None => return,
+        };
+        if !connected && is_transient(&error) {
+            return;
+        }
+        if !is_transient(&error) || *attempts == MAX_RESENDS {
+            log::debug!(
+                "Failed to complete transfer {} from {}: {}",
+                key.1,
+                key.0,
+                error
+            );
+            self.completed.remove(&key);
+            return;
+        }
+        *attempts += 1;
+        self.outbox
+            .push_back((sent.peer, Message::Complete(sent.transfer, hash)));
+    }
+
+    fn interrupt(&mut self, id: TransferId) {
+        let transfer = match self.outgoing.get_mut(&id) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        log::debug!("Transfer {} to {} interrupted", id, transfer.peer);
+        transfer.state = SendState::Interrupted;
+        transfer.in_flight = 0;
+        transfer.resend.clear();
+        // The chunks still queued or in flight are sent again when resuming.
+        self.outbox
+            .retain(|(_, m)| !matches!(m, Message::Chunk(t, ..) if *t == id));
+        for sent in self.sent.values_mut() {
+            if sent.transfer == id && matches!(sent.tag, OFFER | CHUNK) {
+                sent.tag = STALE;
+            }
+        }
+    }
+
+    /// Handles a file message received from `peer`.
+    pub(crate) fn on_message(&mut self, peer: PeerId, data: &[u8]) {
+        let message = match Message::decode(data) {
+            Some(message) => message,
+            None => {
+                log::debug!("Dropping malformed file message from {}", peer);
+                return;
+            }
+        };
+        match message {
+            Message::Offer(id, manifest) => self.on_offer(peer, id, manifest),
+            Message::Chunk(id, offset, data) => self.on_chunk(peer, id, offset, data),
+            Message::Accept(id, offset) => self.on_accept(peer, id, offset),
+            Message::Resend(id, offset) => self.on_resend(peer, id, offset),
+            Message::Complete(id, hash) => self.on_complete(peer, id, hash),
+            Message::Abort(id, ABORT_HASH_MISMATCH) => {
+                self.on_abort(peer, id, FailureReason::HashMismatch)
+            }
+            Message::Abort(id, _) => self.on_abort(peer, id, FailureReason::Aborted),
+            Message::Cancel(id) => self.on_abort(peer, id, FailureReason::CancelledByPeer),
+            Message::Reject(id) => self.on_abort(peer, id, FailureReason::Rejected),
+        }
+    }
+
+    /// Completes the transfer `id` to `peer` if the receiver verified the
+    /// file against the hash of the manifest.
+    fn on_complete(&mut self, peer: PeerId, id: TransferId, hash: [u8; 32]) {
+        match self.outgoing.get(&id) {
+            Some(transfer) if transfer.peer == peer => {}
+            _ => return,
+        }
+        let transfer = self.outgoing.remove(&id).expect("exists");
+        self.forget(&outgoing_record(id));
+        let event = if hash == transfer.manifest.hash {
+            Event::TransferCompleted {
+                id,
+                peer,
+                path: transfer.path,
+                verified: true,
+            }
+        } else {
+            Event::TransferFailed {
+                id,
+                peer,
+                reason: FailureReason::HashMismatch,
+            }
+        };
+        self.events.push_back(event);
+    }
+
+    /// Removes the transfer `id` with `peer`, which the peer ended.
+    fn on_abort(&mut self, peer: PeerId, id: TransferId, reason: FailureReason) {
+        if self.offers.remove(&(peer, id)).is_some() {
+            self.events
+                .push_back(Event::TransferFailed { id, peer, reason });
+        } else if self.incoming.contains_key(&(peer, id)) {
+            self.fail_incoming((peer, id), reason, false);
+        } else if self.outgoing.get(&id).is_some_and(|t| t.peer == peer) {
+            self.fail_outgoing(id, reason, false);
+        }
+    }
+
+    fn on_offer(&mut self, peer: PeerId, id: TransferId, manifest: Manifest) {
+        if let Some((hash, _)) = self.completed.get(&(peer, id)) {
+            // Telling the sender failed before.
+            self.outbox.push_back((peer, Message::Complete(id, *hash)));
+            return;
+        }
+        if let Some(transfer) = self.incoming.get(&(peer, id)) {
+            if transfer.manifest != manifest {
+                return self.fail_incoming((peer, id), FailureReason::InvalidMessage, true);
+            }
+            log::debug!(
+                "Resuming transfer {} from {} at {}",
+                id,
+                peer,
+                transfer.next_offset
+            );
+            let offset = transfer.next_offset;
+            self.outbox.push_back((peer, Message::Accept(id, offset)));
+            return self.next_io((peer, id));
+        }
+        if self.offers.contains_key(&(peer, id)) {
+            return;
+        }
+        let destination = self
+            .auto_accept
+            .as_ref()
+            .and_then(|a| a.destination(&peer, &manifest));
+        if let Some(path) = destination {
+            log::debug!("Accepting file {} from {}", manifest.name, peer);
+            return self.accept(peer, id, manifest, path);
+        }
+        let (sender, receiver) = oneshot::channel();
+        let offer = Offer {
+            manifest: manifest.clone(),
+            answer: receiver,
+        };
+        self.offers.insert((peer, id), offer);
+        self.events.push_back(Event::IncomingTransfer {
+            id,
+            peer,
+            manifest,
+            responder: Responder { sender },
+        });
+    }
+
+    /// Starts receiving the offered file to `path`, accepting it once the
+    /// partial file was created.
+    fn accept(&mut self, peer: PeerId, id: TransferId, manifest: Manifest, path: PathBuf) {
+        let partial = partial_path(&path, id);
+        self.spawn(blocking(move || {
+            let result = File::create(&partial);
+            Done::Created((peer, id), partial, result)
+        }));
+        self.incoming.insert(
+            (peer, id),
+            Incoming {
+                manifest,
+                file: None,
+                pending: VecDeque::new(),
+                persist: false,
+                path,
+                next_offset: 0,
+                ahead: BTreeSet::new(),
+                persisted: 0,
+                resends: HashMap::new(),
+                failed_replies: 0,
+                progress: Progress::new(0),
+            },
+        );
+    }
+
+    fn on_created(
+        &mut self,
+        key: (PeerId, TransferId),
+        partial: PathBuf,
+        result: io::Result<File>,
+    ) {
+        let transfer = match self.incoming.get_mut(&key) {
+            Some(transfer) => transfer,
+            None => {
+                // Cancelled meanwhile, possibly before the file existed.
+                if result.is_ok() {
+                    self.remove(partial);
+                }
+                return;
+            }
+        };
+        match result {
+            Ok(file) => transfer.file = Some(file),
+            Err(e) => return self.fail_incoming(key, FailureReason::Io(e), true),
+        }
+        let (peer, id) = key;
+        self.outbox.push_back((peer, Message::Accept(id, 0)));
+        self.persist_incoming(key);
+        self.next_io(key);
+    }
+
+    fn on_accept(&mut self, peer: PeerId, id: TransferId, offset: u64) {
+        let transfer = match self.outgoing.get_mut(&id) {
+            Some(transfer) if transfer.peer == peer => transfer,
+            _ => return,
+        };
+        if transfer.state != SendState::Offered {
+            return;
+        }
+        if !transfer.manifest.is_boundary(offset) {
+            return self.fail_outgoing(id, FailureReason::InvalidMessage, true);
+        }
+        transfer.state = SendState::Accepted;
+        transfer.next_offset = offset;
+        transfer.acked = offset;
+        transfer.acked_ahead.clear();
+        transfer.progress = Progress::new(offset);
+    }
+
+    fn on_resend(&mut self, peer: PeerId, id: TransferId, offset: u64) {
+        let transfer = match self.outgoing.get_mut(&id) {
+            Some(transfer) if transfer.peer == peer => transfer,
+            _ => return,
+        };
+        if transfer.state != SendState::Accepted {
+            // Resuming sends the chunk again anyway.
+            return;
+        }
+        if offset >= transfer.next_offset || !transfer.manifest.is_boundary(offset) {
+            return self.fail_outgoing(id, FailureReason::InvalidMessage, true);
+        }
+        log::debug!("Sending chunk {} of transfer {} again", offset, id);
+        transfer.resend.insert(offset);
+    }
+
+    fn on_chunk(&mut self, peer: PeerId, id: TransferId, offset: u64, data: Bytes) {
+        let transfer = match self.incoming.get_mut(&(peer, id)) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        let manifest = &transfer.manifest;
+        if offset >= manifest.size
+            || !manifest.is_boundary(offset)
+            || data.len() != manifest.chunk_len(offset)
+        {
+            return self.fail_incoming((peer, id), FailureReason::InvalidMessage, true);
+        }
+        if offset < transfer.next_offset
+            || transfer.ahead.contains(&offset)
+            || transfer.pending.iter().any(|(o, _)| *o == offset)
+        {
+            return;
+        }
+        if transfer.pending.len() >= WINDOW {
+            // Acked chunks only left the sender, ask for this one again once
+            // the disk caught up rather than buffering the file in memory.
+            log::debug!("Dropping chunk {} of transfer {} from {}", offset, id, peer);
+            self.outbox.push_back((peer, Message::Resend(id, offset)));
+            return;
+        }
+        transfer.pending.push_back((offset, data));
+        self.next_io((peer, id));
+    }
+
+    /// Starts the next blocking operation on the file of an incoming
+    /// transfer unless one is running: verifying it once all chunks were
+    /// written, else persisting the progress if asked for, otherwise
+    /// checking and writing the oldest pending chunk.
+    fn next_io(&mut self, key: (PeerId, TransferId)) {
+        let transfer = match self.incoming.get_mut(&key) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        let mut file = match transfer.file.take() {
+            Some(file) => file,
+            None => return,
+        };
+        if transfer.next_offset == transfer.manifest.size {
+            let partial = partial_path(&transfer.path, key.1);
+            return self.spawn(blocking(move || {
+                let result = file.sync_all().and_then(|()| hash_file(&partial));
+                Done::Verified(key, result)
+            }));
+        }
+        let job = match &self.state_dir {
+            Some(dir) if transfer.persist => {
+                transfer.persist = false;
+                let dir = dir.clone();
+                let record = Record {
+                    peer: key.0,
+                    id: key.1,
+                    manifest: transfer.manifest.clone(),
+                    path: transfer.path.clone(),
+                    offset: transfer.next_offset,
+                };
+                blocking(move || {
+                    let result = file
+                        .sync_data()
+                        .and_then(|()| record.save(&dir, &incoming_record(key)));
+                    Done::Persisted(key, file, record.offset, result)
+                })
+            }
+            _ => match transfer.pending.pop_front() {
+                Some((offset, data)) => {
+                    let hash = transfer.manifest.chunk_hash(offset);
+                    blocking(move || {
+                        let result = write_chunk(&mut file, offset, &data, hash);
+                        Done::Written(key, file, offset, result)
+                    })
+                }
+                None => {
+                    transfer.file = Some(file);
+                    return;
+                }
+            },
+        };
+        self.spawn(job);
+    }
+
+    fn on_written(
+        &mut self,
+        key: (PeerId, TransferId),
+        file: File,
+        offset: u64,
+        result: io::Result<bool>,
+    ) {
+        let transfer = match self.incoming.get_mut(&key) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        transfer.file = Some(file);
+        let (peer, id) = key;
+        match result {
+            Ok(true) => {
+                transfer.resends.remove(&offset);
+                transfer.ahead.insert(offset);
+                while transfer.ahead.remove(&transfer.next_offset) {
+                    transfer.next_offset +=
+                        transfer.manifest.chunk_len(transfer.next_offset) as u64;
+                }
+                let interval = PERSIST_INTERVAL * u64::from(transfer.manifest.chunk_size);
+                if transfer.next_offset - transfer.persisted >= interval {
+                    transfer.persist = true;
+                }
+            }
+            Ok(false) => {
+                let resends = transfer.resends.entry(offset).or_default();
+                if *resends == MAX_RESENDS {
+                    return self.fail_incoming(key, FailureReason::HashMismatch, true);
+                }
+                *resends += 1;
+                log::debug!(
+                    "Chunk {} of transfer {} from {} is corrupted",
+                    offset,
+                    id,
+                    peer
+                );
+                self.outbox.push_back((peer, Message::Resend(id, offset)));
+            }
+            Err(e) => return self.fail_incoming(key, FailureReason::Io(e), true),
+        }
+        self.next_io(key);
+    }
+
+    fn on_persisted(
+        &mut self,
+        key: (PeerId, TransferId),
+        file: File,
+        offset: u64,
+        result: io::Result<()>,
+    ) {
+        let transfer = match self.incoming.get_mut(&key) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        transfer.file = Some(file);
+        match result {
+            Ok(()) => transfer.persisted = offset,
+            Err(e) => log::warn!("Failed to persist transfer {}: {}", key.1, e),
+        }
+        self.next_io(key);
+    }
+
+    /// Moves the file of an incoming transfer to its destination if it
+    /// matches the manifest.
+    fn on_verified(&mut self, key: (PeerId, TransferId), result: io::Result<[u8; 32]>) {
+        let transfer = match self.incoming.get(&key) {
+            Some(transfer) => transfer,
+            // Cancelled meanwhile, which removed the partial file.
+            None => return,
+        };
+        let reason = match result {
+            Ok(hash) if hash == transfer.manifest.hash => {
+                let partial = partial_path(&transfer.path, key.1);
+                let path = transfer.path.clone();
+                return self.spawn(blocking(move || {
+                    let result = fs::rename(partial, &path);
+                    Done::Moved(key, path, result)
+                }));
+            }
+            Ok(_) => FailureReason::HashMismatch,
+            Err(e) => FailureReason::Io(e),
+        };
+        self.fail_incoming(key, reason, true);
+    }
+
+    fn on_moved(&mut self, key: (PeerId, TransferId), path: PathBuf, result: io::Result<()>) {
+        if let Err(e) = result {
+            return self.fail_incoming(key, FailureReason::Io(e), true);
+        }
+        let transfer = match self.incoming.remove(&key) {
+            Some(transfer) => transfer,
+            None => {
+                // Cancelled while it was moved, take it back.
+                self.remove(path);
+                return;
+            }
+        };
+        self.forget(&incoming_record(key));
+        let (peer, id) = key;
+        let hash = transfer.manifest.hash;
+        self.outbox.push_back((peer, Message::Complete(id, hash)));
+        self.completed.insert(key, (hash, 0));
+        self.events.push_back(Event::TransferCompleted {
+            id,
+            peer,
+            path,
+            verified: true,
+        });
+    }
+
+    /// Persists up to where an incoming transfer was written, once the data
+    /// is on disk.
+    fn persist_incoming(&mut self, key: (PeerId, TransferId)) {
+        if self.state_dir.is_none() {
+            return;
+        }
+        if let Some(transfer) = self.incoming.get_mut(&key) {
+            transfer.persist = true;
+            self.next_io(key);
+        }
+    }
+
+    /// Removes the persisted record `name`, if any.
+    fn forget(&mut self, name: &str) {
+        if let Some(dir) = &self.state_dir {
+            let path = dir.join(name);
+            self.remove(path);
+        }
+    }
+
+    /// Removes the file at `path` on a worker thread, if it exists.
+    fn remove(&mut self, path: PathBuf) {
+        self.spawn(blocking(move || {
+            let _ = fs::remove_file(path);
+            Done::Removed
+        }));
+    }
+
+    /// Removes an outgoing transfer, telling the receiver if `notify`.
+    fn fail_outgoing(&mut self, id: TransferId, reason: FailureReason, notify: bool) {
+        let transfer = match self.outgoing.remove(&id) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        self.forget(&outgoing_record(id));
+        if notify {
+            self.outbox
+                .push_back((transfer.peer, Message::abort(id, &reason)));
+        }
+        self.events.push_back(Event::TransferFailed {
+            id,
+            peer: transfer.peer,
+            reason,
+        });
+    }
+
+    /// Removes an incoming transfer and its partial file, telling the sender
+    /// if `notify`.
+    fn fail_incoming(&mut self, key: (PeerId, TransferId), reason: FailureReason, notify: bool) {
+        let transfer = match self.incoming.remove(&key) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        self.forget(&incoming_record(key));
+        let (peer, id) = key;
+        drop(transfer.file);
+        self.remove(partial_path(&transfer.path, id));
+        if notify {
+            self.outbox.push_back((peer, Message::abort(id, &reason)));
+        }
+        self.events
+            .push_back(Event::TransferFailed { id, peer, reason });
+    }
+
+    /// Cancels the transfer `id`, removing any partial file and telling the
+    /// peer. Returns whether there was such a transfer.
+    pub(crate) fn cancel(&mut self, id: TransferId) -> bool {
+        if let Some(hashing) = self.hashing.remove(&id) {
+            self.events.push_back(Event::TransferFailed {
+                id,
+                peer: hashing.peer,
+                reason: FailureReason::Cancelled,
+            });
+            return true;
+        }
+        if let Some(peer) = self.outgoing.get(&id).map(|t| t.peer) {
+            self.fail_outgoing(id, FailureReason::Cancelled, false);
+            self.outbox.push_back((peer, Message::Cancel(id)));
+            return true;
+        }
+        if let Some(key) = self.offers.keys().find(|(_, i)| *i == id).copied() {
+            self.offers.remove(&key);
+            self.outbox.push_back((key.0, Message::Cancel(id)));
+            self.events.push_back(Event::TransferFailed {
+                id,
+                peer: key.0,
+                reason: FailureReason::Cancelled,
+            });
+            return true;
+        }
+        match self.incoming.keys().find(|(_, i)| *i == id).copied() {
+            Some((peer, id)) => {
+                self.fail_incoming((peer, id), FailureReason::Cancelled, false);
+                self.outbox.push_back((peer, Message::Cancel(id)));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Handles finished file I/O and the answers to offers, and reports the
+    /// progress of the transfers every progress interval while there are any.
+    pub(crate) fn poll(&mut self, cx: &mut Context<'_>) {
+        self.waker = Some(cx.waker().clone());
+        while let Poll::Ready(Some(done)) = self.io.poll_next_unpin(cx) {
+            match done {
+                Done::Hashed(id, file, result) => self.on_hashed(id, file, result),
+                Done::Read(id, file, offset, result) => self.on_read(id, file, offset, result),
+                Done::Written(key, file, offset, result) => {
+                    self.on_written(key, file, offset, result)
+                }
+                Done::Persisted(key, file, offset, result) => {
+                    self.on_persisted(key, file, offset, result)
+                }
+                Done::Verified(key, result) => self.on_verified(key, result),
+                Done::Created(key, partial, result) => self.on_created(key, partial, result),
+                Done::Moved(key, path, result) => self.on_moved(key, path, result),
+                Done::Removed => {}
+            }
+        }
+        let mut answered = Vec::new();
+        for (key, offer) in &mut self.offers {
+            if let Poll::Ready(answer) = offer.answer.poll_unpin(cx) {
+                answered.push((*key, answer.ok().flatten()));
+            }
+        }
+        for ((peer, id), path) in answered {
+            let manifest = self.offers.remove(&(peer, id)).expect("exists").manifest;
+            match path {
+                Some(path) => self.accept(peer, id, manifest, path),
+                None => {
+                    log::debug!("Rejecting file {} from {}", manifest.name, peer);
+                    self.outbox.push_back((peer, Message::Reject(id)));
+                }
+            }
+        }
+        loop {
+            if let Some(timer) = self.progress_timer.as_mut() {
+                if timer.poll_unpin(cx).is_pending() {
+                    return;
+                }
+                self.progress_timer = None;
+                self.report_progress(Instant::now());
+            }
+            if self.outgoing.is_empty() && self.incoming.is_empty() {
+                return;
+            }
+            self.progress_timer = Some(Delay::new(self.progress_interval));
+        }
+    }
+
+    fn report_progress(&mut self, now: Instant) {
+        for (id, transfer) in &mut self.outgoing {
+            if transfer.state != SendState::Accepted {
+                continue;
+            }
+            let bytes_done = transfer.bytes_done();
+            if let Some(rate) = transfer.progress.report(bytes_done, now) {
+                self.events.push_back(Event::TransferProgress {
+                    id: *id,
+                    peer: transfer.peer,
+                    bytes_done,
+                    total: transfer.manifest.size,
+                    rate,
+                });
+            }
+        }
+        for ((peer, id), transfer) in &mut self.incoming {
+            let bytes_done = transfer.bytes_done();
+            if let Some(rate) = transfer.progress.report(bytes_done, now) {
+                self.events.push_back(Event::TransferProgress {
+                    id: *id,
+                    peer: *peer,
+                    bytes_done,
+                    total: transfer.manifest.size,
+                    rate,
+                });
+            }
+        }
+    }
+
+    /// Queues blocking file I/O, whose outcome is handled in [`Transfers::poll`].
+    fn spawn(&mut self, job: BoxFuture<'static, Done>) {
+        self.io.push(job);
+        self.wake();
+    }
+
+    /// Has the behaviour polled again, so I/O started outside of
+    /// [`Transfers::poll`] is polled too.
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn next_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("libp2p-msg-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn manifest(data: &[u8]) -> Manifest {
+        Manifest {
+            name: "file".to_owned(),
+            size: data.len() as u64,
+            chunk_size: CHUNK_SIZE,
+            hash: Sha256::digest(data).into(),
+            chunk_hashes: data
+                .chunks(CHUNK_SIZE as usize)
+                .map(|chunk| Sha256::digest(chunk).into())
+                .collect(),
+        }
+    }
+
+    fn receiver(dir: &Path) -> Transfers {
+        Transfers::new(&Config::new().with_auto_accept(AutoAccept::new(dir)))
+    }
+
+    /// Hands the next message to the behaviour as `id`, once file I/O
+    /// produced one.
+    fn send(transfers: &mut Transfers, id: u64) -> Message {
+        let (peer, message) = async_std::task::block_on(future::poll_fn(|cx| {
+            transfers.poll(cx);
+            match transfers.next_message() {
+                Some(message) => Poll::Ready(message),
+                None => Poll::Pending,
+            }
+        }));
+        transfers.track(MessageId(id), peer, &message);
+        message
+    }
+
+    /// Polls `transfers` until no file I/O is left running.
+    fn settle(transfers: &mut Transfers) {
+        async_std::task::block_on(future::poll_fn(|cx| {
+            transfers.poll(cx);
+            if transfers.io.is_empty() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }));
+    }
+
+    #[test]
+    fn sends_lost_replies_again() {
+        let dir = temp_dir();
+        let (peer, id) = (PeerId::random(), TransferId(1));
+        let mut transfers = receiver(&dir);
+        transfers.on_message(peer, &Message::Offer(id, manifest(b"data")).encode());
+        for attempt in 0..=u64::from(MAX_RESENDS) {
+            assert_eq!(send(&mut transfers, attempt), Message::Accept(id, 0));
+            transfers.on_send_failed(&MessageId(attempt), SendError::ConnectionClosed, true);
+        }
+        assert_eq!(
+            transfers.next_message(),
+            Some((peer, Message::Abort(id, ABORT_FAILED)))
+        );
+        assert!(matches!(
+            transfers.next_event(),
+            Some(Event::TransferFailed {
+                reason: FailureReason::Send(SendError::ConnectionClosed),
+                ..
+            })
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn answers_offer_again_once_reconnected() {
+        let dir = temp_dir();
+        let (peer, id) = (PeerId::random(), TransferId(1));
+        let offer = Message::Offer(id, manifest(b"data")).encode();
+        let mut transfers = receiver(&dir);
+        transfers.on_message(peer, &offer);
+        assert_eq!(send(&mut transfers, 0), Message::Accept(id, 0));
+        transfers.on_send_failed(&MessageId(0), SendError::ConnectionClosed, false);
+        assert_eq!(transfers.next_message(), None);
+        transfers.on_message(peer, &offer);
+        assert_eq!(
+            transfers.next_message(),
+            Some((peer, Message::Accept(id, 0)))
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn completes_again_once_reconnected() {
+        let dir = temp_dir();
+        let (peer, id) = (PeerId::random(), TransferId(1));
+        let manifest = manifest(b"");
+        let hash = manifest.hash;
+        let offer = Message::Offer(id, manifest).encode();
+        let mut transfers = receiver(&dir);
+        transfers.on_message(peer, &offer);
+        assert_eq!(send(&mut transfers, 0), Message::Accept(id, 0));
+        assert_eq!(send(&mut transfers, 1), Message::Complete(id, hash));
+        assert!(matches!(
+            transfers.next_event(),
+            Some(Event::TransferCompleted { .. })
+        ));
+        transfers.on_send_failed(&MessageId(1), SendError::ConnectionClosed, false);
+        transfers.on_message(peer, &offer);
+        assert_eq!(send(&mut transfers, 2), Message::Complete(id, hash));
+        transfers.on_sent(&MessageId(2));
+        assert!(transfers.completed.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resumes_on_connections_left_a_few_times() {
+        let dir = temp_dir();
+        let path = dir.join("file");
+        fs::write(&path, b"data").unwrap();
+        let peer = PeerId::random();
+        let mut transfers = Transfers::new(&Config::new());
+        let id = transfers.send_file(peer, &path).unwrap();
+        for attempt in 0..=u64::from(MAX_RESENDS) {
+            assert!(matches!(send(&mut transfers, attempt), Message::Offer(i, _) if i == id));
+            transfers.on_send_failed(&MessageId(attempt), SendError::ConnectionClosed, true);
+        }
+        assert_eq!(
+            transfers.next_message(),
+            Some((peer, Message::Abort(id, ABORT_FAILED)))
+        );
+        assert!(matches!(
+            transfers.next_event(),
+            Some(Event::TransferFailed {
+                reason: FailureReason::Send(SendError::ConnectionClosed),
+                ..
+            })
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn waits_for_connection_to_resume() {
+        let dir = temp_dir();
+        let path = dir.join("file");
+        fs::write(&path, b"data").unwrap();
+        let peer = PeerId::random();
+        let mut transfers = Transfers::new(&Config::new());
+        let id = transfers.send_file(peer, &path).unwrap();
+        assert!(matches!(send(&mut transfers, 0), Message::Offer(..)));
+        transfers.on_message(peer, &Message::Accept(id, 0).encode());
+        assert_eq!(
+            send(&mut transfers, 1),
+            Message::Chunk(id, 0, Bytes::from_static(b"data"))
+        );
+        transfers.on_send_failed(&MessageId(1), SendError::ConnectionClosed, false);
+        assert_eq!(transfers.next_message(), None);
+        transfers.on_connected(peer);
+        assert!(matches!(send(&mut transfers, 2), Message::Offer(..)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_file_not_matching_manifest() {
+        let dir = temp_dir();
+        let (peer, id) = (PeerId::random(), TransferId(1));
+        let mut manifest = manifest(b"data");
+        manifest.hash = [0; 32];
+        let mut transfers = receiver(&dir);
+        transfers.on_message(peer, &Message::Offer(id, manifest).encode());
+        assert_eq!(send(&mut transfers, 0), Message::Accept(id, 0));
+        let chunk = Message::Chunk(id, 0, Bytes::from_static(b"data"));
+        transfers.on_message(peer, &chunk.encode());
+        assert_eq!(
+            send(&mut transfers, 1),
+            Message::Abort(id, ABORT_HASH_MISMATCH)
+        );
+        assert!(matches!(
+            transfers.next_event(),
+            Some(Event::TransferFailed {
+                reason: FailureReason::HashMismatch,
+                ..
+            })
+        ));
+        settle(&mut transfers);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cancelling_while_creating_leaves_no_file() {
+        let dir = temp_dir();
+        let (peer, id) = (PeerId::random(), TransferId(1));
+        let mut transfers = receiver(&dir);
+        transfers.on_message(peer, &Message::Offer(id, manifest(b"data")).encode());
+        assert!(transfers.cancel(id));
+        assert_eq!(transfers.next_message(), Some((peer, Message::Cancel(id))));
+        settle(&mut transfers);
+        assert_eq!(transfers.next_message(), None);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sender_fails_completion_with_other_hash() {
+        let dir = temp_dir();
+        let path = dir.join("file");
+        fs::write(&path, b"data").unwrap();
+        let peer = PeerId::random();
+        let mut transfers = Transfers::new(&Config::new());
+        let id = transfers.send_file(peer, &path).unwrap();
+        assert!(matches!(send(&mut transfers, 0), Message::Offer(..)));
+        transfers.on_message(peer, &Message::Complete(id, [0; 32]).encode());
+        assert!(matches!(
+            transfers.next_event(),
+            Some(Event::TransferFailed {
+                reason: FailureReason::HashMismatch,
+                ..
+            })
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sender_progress_counts_chunks_sent() {
+        let dir = temp_dir();
+        let path = dir.join("file");
+        fs::write(&path, vec![0; CHUNK_SIZE as usize + 1]).unwrap();
+        let peer = PeerId::random();
+        let mut transfers = Transfers::new(&Config::new());
+        let id = transfers.send_file(peer, &path).unwrap();
+        assert!(matches!(send(&mut transfers, 0), Message::Offer(..)));
+        transfers.on_message(peer, &Message::Accept(id, 0).encode());
+        assert!(matches!(send(&mut transfers, 1), Message::Chunk(_, 0, _)));
+        assert!(matches!(send(&mut transfers, 2), Message::Chunk(..)));
+        assert_eq!(transfers.outgoing[&id].bytes_done(), 0);
+        transfers.on_sent(&MessageId(2));
+        assert_eq!(transfers.outgoing[&id].bytes_done(), 1);
+        transfers.on_sent(&MessageId(1));
+        assert_eq!(
+            transfers.outgoing[&id].bytes_done(),
+            u64::from(CHUNK_SIZE) + 1
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sender_reports_hash_mismatch_of_receiver() {
+        let dir = temp_dir();
+        let path = dir.join("file");
+        fs::write(&path, b"data").unwrap();
+        let peer = PeerId::random();
+        let mut transfers = Transfers::new(&Config::new());
+        let id = transfers.send_file(peer, &path).unwrap();
+        assert!(matches!(send(&mut transfers, 0), Message::Offer(..)));
+        let abort = Message::Abort(id, ABORT_HASH_MISMATCH);
+        transfers.on_message(peer, &abort.encode());
+        assert!(matches!(
+            transfers.next_event(),
+            Some(Event::TransferFailed {
+                reason: FailureReason::HashMismatch,
+                ..
+            })
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn limits_chunks_waiting_for_the_disk() {
+        let dir = temp_dir();
+        let (peer, id) = (PeerId::random(), TransferId(1));
+        let chunks = WINDOW as u64 + 2;
+        let data = vec![0; (chunks * u64::from(CHUNK_SIZE)) as usize];
+        let mut transfers = receiver(&dir);
+        transfers.on_message(peer, &Message::Offer(id, manifest(&data)).encode());
+        assert_eq!(send(&mut transfers, 0), Message::Accept(id, 0));
+        // The first chunk is written right away, the next ones wait.
+        for chunk in data.chunks(CHUNK_SIZE as usize).enumerate() {
+            let offset = chunk.0 as u64 * u64::from(CHUNK_SIZE);
+            let message = Message::Chunk(id, offset, Bytes::copy_from_slice(chunk.1));
+            transfers.on_message(peer, &message.encode());
+        }
+        let last = (chunks - 1) * u64::from(CHUNK_SIZE);
+        assert_eq!(
+            transfers.next_message(),
+            Some((peer, Message::Resend(id, last)))
+        );
+        assert_eq!(transfers.incoming[&(peer, id)].pending.len(), WINDOW);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
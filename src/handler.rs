@@ -1,17 +1,550 @@
+use crate::file::AutoAccept;
 use crate::protocol;
+use crate::stream::RawStream;
+use crate::{Compression, MessageId, RequestId, ResponseChannel, ResponseError, SendError};
+use bytes::Bytes;
+use futures::channel::oneshot;
+use futures::future::{self, BoxFuture};
+use futures::prelude::*;
+use futures::stream::{BoxStream, FuturesUnordered, SelectAll};
+use futures_timer::Delay;
+use libp2p::core::identity::Keypair;
+use libp2p::core::upgrade::{NegotiationError, UpgradeError};
 use libp2p::swarm::{
     ConnectionHandler, ConnectionHandlerEvent, ConnectionHandlerUpgrErr, KeepAlive,
-    SubstreamProtocol,
+    NegotiatedSubstream, SubstreamProtocol,
 };
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// The configuration for a [`Behaviour`](crate::Behaviour).
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The largest inbound message we are willing to read.
+    max_message_size: usize,
+    /// Whether to open at most one outbound substream at a time per connection.
+    sequential_outbound: bool,
+    /// How long a request may take, and how long we wait for the application's response.
+    request_timeout: Duration,
+    /// Protocol names are `<protocol_prefix>/<version>`.
+    protocol_prefix: String,
+    /// Message protocol versions in order of preference.
+    versions: Vec<protocol::Version>,
+    /// How many messages and requests to hold per disconnected peer.
+    pending_queue_size: usize,
+    /// How long a held message or request waits for a connection.
+    pending_ttl: Duration,
+    /// How long an idle connection is kept alive.
+    idle_timeout: Duration,
+    /// How long protocol negotiation on a substream may take.
+    negotiation_timeout: Duration,
+    /// How long reading and writing a single frame may take.
+    timeouts: protocol::Timeouts,
+    /// Inbound substreams per connection beyond which new ones are refused.
+    max_inbound_streams: usize,
+    /// Outbound substreams per connection beyond which messages wait.
+    max_outbound_streams: usize,
+    /// How many unfinished messages a peer may have.
+    outbound_buffer: usize,
+    /// Whether messages are written to one long-lived substream per connection.
+    persistent_stream: bool,
+    /// The algorithm to compress outbound payloads with, and the payload size
+    /// from which on it is applied.
+    compression: Option<(Compression, usize)>,
+    /// The key outbound envelopes are signed with.
+    keypair: Option<Keypair>,
+    /// The key sealed inbound payloads are decrypted with.
+    decryption_key: Option<Keypair>,
+    /// Which file offers are accepted without asking the application.
+    auto_accept: Option<AutoAccept>,
+    /// Where the progress of file transfers is persisted.
+    transfer_state_dir: Option<PathBuf>,
+    /// How often the progress of file transfers is reported.
+    progress_interval: Duration,
+}
+
+impl Config {
+    /// Creates a new `Config` with the following default settings:
+    ///
+    ///   * [`Config::with_max_message_size`] 16 MiB
+    ///   * [`Config::with_sequential_outbound`] false
+    ///   * [`Config::with_request_timeout`] 10s
+    ///   * [`Config::with_protocol_prefix`] `/p2p/msg`
+    ///   * [`Config::with_versions`] 2.0.0, 1.1.0, 1.0.0
+    ///   * [`Config::with_pending_queue`] 64 per peer, 30s TTL
+    ///   * [`Config::with_idle_timeout`] 10s
+    ///   * [`Config::with_negotiation_timeout`] 10s
+    ///   * [`Config::with_write_timeout`] 10s
+    ///   * [`Config::with_read_timeout`] 10s
+    ///   * [`Config::with_max_inbound_streams`] 32
+    ///   * [`Config::with_max_outbound_streams`] 16
+    ///   * [`Config::with_outbound_buffer`] 256
+    ///   * [`Config::with_persistent_stream`] false
+    ///   * [`Config::with_compression`] none
+    ///   * [`Config::with_signing`] none
+    ///   * [`Config::with_decryption`] none
+    ///   * [`Config::with_auto_accept`] none, every offer is reported
+    ///   * [`Config::with_transfer_state_dir`] none, transfers only resume
+    ///     while the behaviour lives
+    ///   * [`Config::with_progress_interval`] 1 s
+    pub fn new() -> Self {
+        Self {
+            max_message_size: 16 * 1024 * 1024,
+            sequential_outbound: false,
+            request_timeout: Duration::from_secs(10),
+            protocol_prefix: "/p2p/msg".to_string(),
+            versions: vec![
+                protocol::Version::V2_0,
+                protocol::Version::V1_1,
+                protocol::Version::V1_0,
+            ],
+            pending_queue_size: 64,
+            pending_ttl: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(10),
+            negotiation_timeout: Duration::from_secs(10),
+            timeouts: protocol::Timeouts {
+                read: Duration::from_secs(10),
+                write: Duration::from_secs(10),
+            },
+            max_inbound_streams: 32,
+            max_outbound_streams: 16,
+            outbound_buffer: 256,
+            persistent_stream: false,
+            compression: None,
+            keypair: None,
+            decryption_key: None,
+            auto_accept: None,
+            transfer_state_dir: None,
+            progress_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Sets the maximum size of an inbound message.
+    ///
+    /// Larger messages are rejected before their payload is read and
+    /// reported as [`InboundError::MessageTooLarge`](crate::InboundError::MessageTooLarge).
+    pub fn with_max_message_size(mut self, n: usize) -> Self {
+        self.max_message_size = n;
+        self
+    }
+
+    /// Sets whether messages to a peer are sent one substream at a time.
+    ///
+    /// Messages to a peer are always handed to its connection in the order
+    /// they were passed to [`Behaviour::send`](crate::Behaviour::send), but by
+    /// default each one is sent on its own substream concurrently, so the
+    /// remote may read them in any order. With this option the next substream
+    /// on a connection is only opened once the previous one completed, and a
+    /// peer's messages stay on one connection until it completed them, also
+    /// when a direct connection to the peer appears meanwhile.
+    /// Together with the acks of [`Version::V1_1`](crate::Version::V1_1) this
+    /// guarantees the remote emits [`Event::Received`](crate::Event::Received)
+    /// in send order.
+    ///
+    /// Messages sent with [`Behaviour::send_on`](crate::Behaviour::send_on)
+    /// go to the connection they name, so they may overtake, or be overtaken
+    /// by, messages sent with [`Behaviour::send`](crate::Behaviour::send).
+    pub fn with_sequential_outbound(mut self, b: bool) -> Self {
+        self.sequential_outbound = b;
+        self
+    }
+
+    /// Sets the request timeout.
+    ///
+    /// An outbound request fails with [`SendError::Timeout`] if no response
+    /// arrives in time, and an inbound request fails with
+    /// [`ResponseError::Timeout`] if the application doesn't call
+    /// [`Behaviour::respond`](crate::Behaviour::respond) in time.
+    pub fn with_request_timeout(mut self, d: Duration) -> Self {
+        self.request_timeout = d;
+        self
+    }
+
+    /// Sets the prefix of the protocol names.
+    ///
+    /// Messages are negotiated as `<prefix>/<version>` and requests as
+    /// `<prefix>/req/1.0.0`. Applications sharing a swarm use distinct
+    /// prefixes to keep their messages apart.
+    pub fn with_protocol_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.protocol_prefix = prefix.into();
+        self
+    }
+
+    /// Sets the message protocol versions to advertise, most preferred first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `versions` is empty.
+    pub fn with_versions(mut self, versions: impl IntoIterator<Item = protocol::Version>) -> Self {
+        self.versions = versions.into_iter().collect();
+        assert!(
+            !self.versions.is_empty(),
+            "at least one version is required"
+        );
+        self
+    }
+
+    /// Sets how messages and requests to disconnected peers are handled.
+    ///
+    /// Up to `size` of them are held per peer while the peer is dialed and
+    /// sent once a connection is established. They fail with
+    /// [`SendError::DialFailed`](crate::SendError::DialFailed) if dialing
+    /// fails and with [`SendError::Expired`](crate::SendError::Expired) if no
+    /// connection exists after `ttl`. A `size` of 0 fails them right away
+    /// with [`SendError::NotConnected`](crate::SendError::NotConnected).
+    pub fn with_pending_queue(mut self, size: usize, ttl: Duration) -> Self {
+        self.pending_queue_size = size;
+        self.pending_ttl = ttl;
+        self
+    }
+
+    /// Sets how long a connection is kept alive without any message or
+    /// request in flight.
+    ///
+    /// Connections to peers pinned with
+    /// [`Behaviour::pin`](crate::Behaviour::pin) are kept alive regardless.
+    pub fn with_idle_timeout(mut self, d: Duration) -> Self {
+        self.idle_timeout = d;
+        self
+    }
+
+    /// Sets how long negotiating the protocol of a substream may take.
+    pub fn with_negotiation_timeout(mut self, d: Duration) -> Self {
+        self.negotiation_timeout = d;
+        self
+    }
+
+    /// Sets how long writing a message, request, response or ack may take.
+    ///
+    /// Outbound messages and requests that time out fail with
+    /// [`SendError::Timeout`], responses with [`ResponseError::Timeout`].
+    pub fn with_write_timeout(mut self, d: Duration) -> Self {
+        self.timeouts.write = d;
+        self
+    }
+
+    /// Sets how long reading an inbound message or request, or the ack of an
+    /// outbound message, may take.
+    ///
+    /// Inbound messages that time out are reported as
+    /// [`InboundError::Timeout`](crate::InboundError::Timeout), outbound ones
+    /// fail with [`SendError::Timeout`].
+    pub fn with_read_timeout(mut self, d: Duration) -> Self {
+        self.timeouts.read = d;
+        self
+    }
+
+    /// Sets how many inbound substreams a connection reads, holds for a
+    /// response or has open as raw or persistent streams at the same time.
+    ///
+    /// Further substreams are refused during negotiation, so the remote sees
+    /// them fail with [`SendError::UnsupportedProtocols`], or closed right
+    /// after negotiation if others took the last slot meanwhile.
+    pub fn with_max_inbound_streams(mut self, n: usize) -> Self {
+        self.max_inbound_streams = n;
+        self
+    }
+
+    /// Sets how many outbound substreams a connection opens at the same time.
+    ///
+    /// Further messages and requests wait in the connection's queue.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    pub fn with_max_outbound_streams(mut self, n: usize) -> Self {
+        assert!(n > 0, "at least one outbound stream is required");
+        self.max_outbound_streams = n;
+        self
+    }
+
+    /// Sets how many messages to a peer may be unfinished at a time.
+    ///
+    /// A message occupies its peer's buffer from
+    /// [`Behaviour::send`](crate::Behaviour::send) until its outcome is
+    /// reported. [`Control::send`](crate::Control::send) waits for room,
+    /// while the methods of the behaviour fail with
+    /// [`SendError::BufferFull`](crate::SendError::BufferFull).
+    pub fn with_outbound_buffer(mut self, n: usize) -> Self {
+        self.outbound_buffer = n;
+        self
+    }
+
+    /// Sets whether messages are sent on one long-lived substream per
+    /// connection instead of a new substream each.
+    ///
+    /// This saves a protocol negotiation per message. Frames are written in
+    /// order, but the remote doesn't acknowledge them: a message succeeds
+    /// once its frame was flushed, so
+    /// [`Event::SendSucceeded`](crate::Event::SendSucceeded) only means it was
+    /// written and carries `acked: false`. The substream is re-opened after
+    /// an error, and if the remote doesn't support it messages fall back to a
+    /// substream each. Requests always use their own substream. Inbound
+    /// persistent substreams count against
+    /// [`Config::with_max_inbound_streams`] and are reported as
+    /// [`Version::V2_0`](crate::Version::V2_0). Without that version among
+    /// [`Config::with_versions`], persistent substreams are neither opened
+    /// nor accepted.
+    pub fn with_persistent_stream(mut self, b: bool) -> Self {
+        self.persistent_stream = b;
+        self
+    }
+
+    /// Sets the algorithm to compress message payloads of at least
+    /// `threshold` bytes with.
+    ///
+    /// Support is negotiated through the protocol name, e.g.
+    /// `/p2p/msg/zstd/2.0.0`, so remotes without the algorithm receive
+    /// payloads uncompressed. Payloads that don't shrink are sent as they
+    /// are, and every compressed payload is marked in its envelope. Inbound
+    /// payloads are decompressed with any algorithm compiled in, and count
+    /// against [`Config::with_max_message_size`] after decompression.
+    pub fn with_compression(mut self, compression: Compression, threshold: usize) -> Self {
+        self.compression = Some((compression, threshold));
+        self
+    }
+
+    /// Signs the envelope of every outbound message with `keypair`.
+    ///
+    /// The signature covers the envelope and the payload, so the message
+    /// proves its author even when it was forwarded or stored by other
+    /// peers. Receivers report the author in
+    /// [`Event::Received`](crate::Event::Received) and drop messages with a
+    /// bad signature as [`Event::InvalidSignature`](crate::Event::InvalidSignature).
+    /// Signed messages are verified regardless of this option. Versions
+    /// without envelopes carry no signature.
+    pub fn with_signing(mut self, keypair: Keypair) -> Self {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    /// Decrypts inbound payloads sealed to us, see
+    /// [`Metadata::with_sealed`](crate::Metadata::with_sealed), with
+    /// `keypair`.
+    ///
+    /// `keypair` must be the identity of the swarm, since senders derive the
+    /// key they encrypt to from our peer ID. Without it sealed messages are
+    /// reported as [`InboundError::Decryption`](crate::InboundError::Decryption).
+    pub fn with_decryption(mut self, keypair: Keypair) -> Self {
+        self.decryption_key = Some(keypair);
+        self
+    }
+
+    /// Accepts the file offers matching `policy` right away, instead of
+    /// reporting them as [`file::Event::IncomingTransfer`](crate::file::Event::IncomingTransfer).
+    pub fn with_auto_accept(mut self, policy: AutoAccept) -> Self {
+        self.auto_accept = Some(policy);
+        self
+    }
+
+    /// Persists the progress of file transfers in `dir`, which must exist,
+    /// so that interrupted transfers resume after a restart.
+    ///
+    /// Transfers persisted by a previous run are offered to their peer again
+    /// once connected.
+    pub fn with_transfer_state_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.transfer_state_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets how often [`file::Event::TransferProgress`](crate::file::Event::TransferProgress)
+    /// is reported for every file transfer that made progress.
+    pub fn with_progress_interval(mut self, interval: Duration) -> Self {
+        self.progress_interval = interval;
+        self
+    }
+
+    pub(crate) fn sequential_outbound(&self) -> bool {
+        self.sequential_outbound
+    }
+
+    pub(crate) fn outbound_buffer(&self) -> usize {
+        self.outbound_buffer
+    }
+
+    /// How many outbound substreams a connection may have in flight.
+    fn outbound_limit(&self) -> usize {
+        if self.sequential_outbound {
+            1
+        } else {
+            self.max_outbound_streams
+        }
+    }
+
+    /// The upper bound for a whole message substream, from negotiation to ack.
+    fn message_timeout(&self) -> Duration {
+        self.negotiation_timeout + self.timeouts.read + self.timeouts.write
+    }
+
+    pub(crate) fn keypair(&self) -> Option<&Keypair> {
+        self.keypair.as_ref()
+    }
+
+    pub(crate) fn decryption_key(&self) -> Option<&Keypair> {
+        self.decryption_key.as_ref()
+    }
+
+    pub(crate) fn auto_accept(&self) -> Option<&AutoAccept> {
+        self.auto_accept.as_ref()
+    }
+
+    pub(crate) fn transfer_state_dir(&self) -> Option<&Path> {
+        self.transfer_state_dir.as_deref()
+    }
+
+    pub(crate) fn progress_interval(&self) -> Duration {
+        self.progress_interval
+    }
+
+    pub(crate) fn pending_queue_size(&self) -> usize {
+        self.pending_queue_size
+    }
+
+    pub(crate) fn pending_ttl(&self) -> Duration {
+        self.pending_ttl
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Substream counters of all connections, shared by the handlers.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub inbound: AtomicUsize,
+    pub outbound: AtomicUsize,
+    pub waiting: AtomicUsize,
+    pub refused: AtomicUsize,
+}
+
+impl Counters {
+    pub fn stats(&self) -> Stats {
+        Stats {
+            inbound_streams: self.inbound.load(Ordering::Relaxed),
+            outbound_streams: self.outbound.load(Ordering::Relaxed),
+            waiting_outbound: self.waiting.load(Ordering::Relaxed),
+            refused_inbound: self.refused.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of the substreams of all connections, see
+/// [`Behaviour::stats`](crate::Behaviour::stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Inbound substreams being read, waiting for a response or open as raw
+    /// or persistent streams.
+    pub inbound_streams: usize,
+    /// Outbound substreams being negotiated or written.
+    pub outbound_streams: usize,
+    /// Messages and requests waiting for an outbound substream.
+    pub waiting_outbound: usize,
+    /// Inbound substreams refused so far because of
+    /// [`Config::with_max_inbound_streams`].
+    pub refused_inbound: usize,
+}
 
 #[derive(Debug)]
 pub enum Success {
     OK,
 }
 
+/// Events sent from the behaviour to a [`Handler`].
+#[derive(Debug)]
+pub enum HandlerIn {
+    /// Open an outbound substream and send the message on it.
+    Send {
+        id: MessageId,
+        envelope: Bytes,
+        data: Bytes,
+    },
+    /// Open an outbound substream, send the request and read the response.
+    Request { id: RequestId, data: Vec<u8> },
+    /// Keep the connection alive even when idle, or stop doing so.
+    Pin(bool),
+    /// Open a raw stream and hand it to the sender.
+    OpenStream(StreamSender),
+}
+
+/// Receives a raw stream opened with [`HandlerIn::OpenStream`].
+pub type StreamSender = oneshot::Sender<Result<RawStream, SendError>>;
+
+/// Events reported by a [`Handler`] to the behaviour.
+#[derive(Debug)]
+pub enum HandlerEvent {
+    /// A message was received on an inbound substream.
+    Received(protocol::MsgContent, protocol::Version),
+    /// The outbound substream for the message completed, with `true` if the
+    /// remote acknowledged the message.
+    SendSucceeded(MessageId, bool),
+    /// The message could not be sent.
+    SendFailed(MessageId, SendError),
+    /// Reading an inbound message failed.
+    InboundFailed(ConnectionHandlerUpgrErr<protocol::RecvError>),
+    /// A request was received and awaits a response on `channel`.
+    Request {
+        id: RequestId,
+        data: Vec<u8>,
+        channel: ResponseChannel,
+    },
+    /// The remote answered an outbound request.
+    Response(RequestId, Vec<u8>),
+    /// An outbound request failed.
+    RequestFailed(RequestId, SendError),
+    /// The response to an inbound request was written.
+    ResponseSent(RequestId),
+    /// No response could be written for an inbound request.
+    ResponseFailed(RequestId, ResponseError),
+    /// The remote opened a raw stream.
+    InboundStream(RawStream),
+}
+
+/// Identifies what an outbound substream was opened for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum OutboundId {
+    Message(MessageId),
+    Request(RequestId),
+}
+
+/// What an outbound substream is requested for.
+#[derive(Debug)]
+pub enum OpenInfo {
+    Outbound(OutboundId),
+    Stream(StreamSender),
+    Persistent,
+}
+
+type WriteFuture = BoxFuture<'static, (MessageId, Result<NegotiatedSubstream, SendError>)>;
+
+/// The long-lived outbound substream of [`Config::with_persistent_stream`].
+enum Persistent {
+    /// Not opened yet, or closed after an error.
+    Closed,
+    Opening,
+    Idle(NegotiatedSubstream),
+    Writing(WriteFuture),
+    /// The remote doesn't support it, so messages use a substream each.
+    Unsupported,
+}
+
+type ResponseFuture = BoxFuture<'static, (RequestId, Result<(), ResponseError>)>;
+
 pub struct Handler {
+    config: Config,
+    protocols: Arc<protocol::Protocols>,
+    /// Source of IDs for inbound requests, shared with the behaviour.
+    request_ids: Arc<AtomicU64>,
+    /// Substream counters, shared with the behaviour and other handlers.
+    counters: Arc<Counters>,
+    inbound_slots: Arc<protocol::InboundSlots>,
     /// Outbound Inbound events
     #[allow(clippy::type_complexity)]
     queued_events: VecDeque<
@@ -22,79 +555,609 @@ pub struct Handler {
             <Self as ConnectionHandler>::Error,
         >,
     >,
+    /// Messages and requests waiting for an outbound substream, oldest first.
+    waiting_outbound: VecDeque<(OutboundId, protocol::MsgOutbound)>,
+    /// Number of outbound substreams requested but not completed.
+    outbound_in_flight: usize,
+    /// Messages and requests handed to this handler that have not completed yet.
+    pending_outbound: HashSet<OutboundId>,
+    /// Inbound requests waiting for the application's response.
+    pending_responses: FuturesUnordered<ResponseFuture>,
+    /// IDs of the requests in `pending_responses`.
+    pending_inbound: HashSet<RequestId>,
+    /// Whether the connection is kept alive even when idle.
+    pinned: bool,
+    /// `Yes` while busy, otherwise until the idle timeout elapsed.
+    keep_alive: KeepAlive,
+    /// Cloned into every raw stream of the connection to count them.
+    streams: Arc<()>,
+    persistent: Persistent,
+    /// How payloads on the persistent substream are compressed, as negotiated.
+    persistent_compression: Option<(Compression, usize)>,
+    /// Messages waiting for the persistent substream, oldest first.
+    persistent_queue: VecDeque<(MessageId, Bytes, Bytes)>,
+    /// Frames read from the remote's persistent substreams.
+    inbound_frames: SelectAll<BoxStream<'static, Result<Vec<u8>, protocol::RecvError>>>,
 }
 
 impl Handler {
-    pub fn new() -> Self {
+    pub fn new(config: Config, request_ids: Arc<AtomicU64>, counters: Arc<Counters>) -> Self {
+        let protocols = protocol::Protocols::new(
+            &config.protocol_prefix,
+            &config.versions,
+            config.compression,
+        );
+        let persistent = if protocols.has_persistent() {
+            Persistent::Closed
+        } else {
+            Persistent::Unsupported
+        };
         Handler {
+            inbound_slots: Arc::new(protocol::InboundSlots::new(
+                config.max_inbound_streams,
+                counters.clone(),
+            )),
+            counters,
+            protocols: Arc::new(protocols),
+            request_ids,
             queued_events: Default::default(),
+            waiting_outbound: Default::default(),
+            outbound_in_flight: 0,
+            pending_outbound: Default::default(),
+            pending_responses: Default::default(),
+            pending_inbound: Default::default(),
+            pinned: false,
+            streams: Arc::new(()),
+            persistent,
+            persistent_compression: None,
+            persistent_queue: Default::default(),
+            inbound_frames: Default::default(),
+            keep_alive: KeepAlive::Until(Instant::now() + config.idle_timeout),
+            config,
+        }
+    }
+
+    /// Removes and returns the messages and requests that never completed on this connection.
+    pub fn take_pending(&mut self) -> impl Iterator<Item = OutboundId> {
+        std::mem::take(&mut self.pending_outbound).into_iter()
+    }
+
+    /// Removes and returns the inbound requests that were never answered on this connection.
+    pub fn take_pending_responses(&mut self) -> impl Iterator<Item = RequestId> {
+        std::mem::take(&mut self.pending_inbound).into_iter()
+    }
+
+    /// Whether any message or request is still waiting or in flight.
+    fn is_busy(&self) -> bool {
+        self.outbound_in_flight > 0
+            || !self.waiting_outbound.is_empty()
+            || !self.pending_responses.is_empty()
+            || !self.queued_events.is_empty()
+            || Arc::strong_count(&self.streams) > 1
+            || !self.persistent_queue.is_empty()
+            || matches!(
+                self.persistent,
+                Persistent::Opening | Persistent::Writing(_)
+            )
+    }
+
+    /// Drives the persistent substream, returning the next event if any.
+    #[allow(clippy::type_complexity)]
+    fn poll_persistent(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Option<ConnectionHandlerEvent<protocol::MsgOutbound, OpenInfo, HandlerEvent, io::Error>>
+    {
+        loop {
+            match std::mem::replace(&mut self.persistent, Persistent::Closed) {
+                Persistent::Writing(mut write) => {
+                    let (id, result) = match write.poll_unpin(cx) {
+                        Poll::Ready(output) => output,
+                        Poll::Pending => {
+                            self.persistent = Persistent::Writing(write);
+                            return None;
+                        }
+                    };
+                    self.pending_outbound.remove(&OutboundId::Message(id));
+                    let event = match result {
+                        Ok(socket) => {
+                            self.persistent = Persistent::Idle(socket);
+                            HandlerEvent::SendSucceeded(id, false)
+                        }
+                        Err(e) => HandlerEvent::SendFailed(id, e),
+                    };
+                    return Some(ConnectionHandlerEvent::Custom(event));
+                }
+                Persistent::Idle(mut socket) => {
+                    let (id, envelope, data) = match self.persistent_queue.pop_front() {
+                        Some(message) => message,
+                        None => {
+                            self.persistent = Persistent::Idle(socket);
+                            return None;
+                        }
+                    };
+                    self.counters.waiting.fetch_sub(1, Ordering::Relaxed);
+                    let write_timeout = self.config.timeouts.write;
+                    let parts = protocol::compress(self.persistent_compression, envelope, data);
+                    let write =
+                        async move {
+                            let write = protocol::send_frame(&mut socket, parts);
+                            let result =
+                                match protocol::timeout(write_timeout, write, protocol::timed_out)
+                                    .await
+                                {
+                                    Ok(()) => Ok(socket),
+                                    Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                                        Err(SendError::Timeout)
+                                    }
+                                    Err(e) => Err(SendError::Io(e)),
+                                };
+                            (id, result)
+                        };
+                    self.persistent = Persistent::Writing(write.boxed());
+                }
+                Persistent::Closed if !self.persistent_queue.is_empty() => {
+                    self.persistent = Persistent::Opening;
+                    let upgrade = protocol::MsgOutbound::Persistent {
+                        protocols: self.protocols.clone(),
+                    };
+                    let protocol = SubstreamProtocol::new(upgrade, OpenInfo::Persistent)
+                        .with_timeout(self.config.negotiation_timeout);
+                    return Some(ConnectionHandlerEvent::OutboundSubstreamRequest { protocol });
+                }
+                state => {
+                    self.persistent = state;
+                    return None;
+                }
+            }
         }
     }
+
+    /// Sends the messages waiting for the persistent substream on a substream each.
+    fn fall_back_to_substreams(&mut self) {
+        self.persistent = Persistent::Unsupported;
+        for (id, envelope, data) in self.persistent_queue.drain(..) {
+            let upgrade = protocol::MsgOutbound::Message {
+                envelope,
+                data,
+                protocols: self.protocols.clone(),
+                timeouts: self.config.timeouts,
+            };
+            self.waiting_outbound
+                .push_back((OutboundId::Message(id), upgrade));
+        }
+    }
+
+    fn on_request(&mut self, data: Vec<u8>, mut socket: NegotiatedSubstream, slot: protocol::Slot) {
+        let id = RequestId(self.request_ids.fetch_add(1, Ordering::Relaxed));
+        let (sender, receiver) = oneshot::channel();
+        let timeout = Delay::new(self.config.request_timeout);
+        let write_timeout = self.config.timeouts.write;
+        let response = async move {
+            let response = match future::select(receiver, timeout).await {
+                future::Either::Left((Ok(response), _)) => response,
+                future::Either::Left((Err(oneshot::Canceled), _)) => {
+                    return (id, Err(ResponseError::Omission))
+                }
+                future::Either::Right(((), _)) => return (id, Err(ResponseError::Timeout)),
+            };
+            let result = protocol::timeout(
+                write_timeout,
+                protocol::send(&mut socket, response).map_err(ResponseError::Io),
+                || ResponseError::Timeout,
+            )
+            .await;
+            drop(slot);
+            (id, result.map(|_| ()))
+        };
+        self.pending_inbound.insert(id);
+        self.pending_responses.push(response.boxed());
+        self.queued_events
+            .push_back(ConnectionHandlerEvent::Custom(HandlerEvent::Request {
+                id,
+                data,
+                channel: ResponseChannel { sender },
+            }));
+    }
 }
 
 impl Default for Handler {
     fn default() -> Self {
-        Self::new()
+        Self::new(Config::default(), Default::default(), Default::default())
     }
 }
 
 impl ConnectionHandler for Handler {
-    type InEvent = protocol::MsgContent;
-    type OutEvent = protocol::MsgContent;
+    type InEvent = HandlerIn;
+    type OutEvent = HandlerEvent;
     type Error = std::io::Error;
-    type InboundProtocol = protocol::MsgContent;
-    type OutboundProtocol = protocol::MsgContent;
-    type OutboundOpenInfo = ();
+    type InboundProtocol = protocol::MsgInbound;
+    type OutboundProtocol = protocol::MsgOutbound;
+    type OutboundOpenInfo = OpenInfo;
     type InboundOpenInfo = ();
 
-    fn listen_protocol(&self) -> SubstreamProtocol<protocol::MsgContent, ()> {
+    fn listen_protocol(&self) -> SubstreamProtocol<protocol::MsgInbound, ()> {
         SubstreamProtocol::new(
-            protocol::MsgContent {
-                data: Default::default(),
+            protocol::MsgInbound {
+                protocols: self.protocols.clone(),
+                max_message_size: self.config.max_message_size,
+                timeouts: self.config.timeouts,
+                slots: self.inbound_slots.clone(),
             },
             (),
         )
+        .with_timeout(self.config.message_timeout())
     }
 
     //protocol::InboundUpgrade::Output
-    fn inject_fully_negotiated_inbound(&mut self, output: Vec<u8>, (): ()) {
+    fn inject_fully_negotiated_inbound(&mut self, output: protocol::Inbound, (): ()) {
+        match output {
+            protocol::Inbound::Message(content, version) => {
+                self.queued_events.push_back(ConnectionHandlerEvent::Custom(
+                    HandlerEvent::Received(content, version),
+                ));
+            }
+            protocol::Inbound::Request(data, socket, slot) => self.on_request(data, socket, slot),
+            protocol::Inbound::Persistent(socket, slot) => {
+                let frames = protocol::recv_frames(
+                    socket,
+                    self.config.max_message_size,
+                    self.config.timeouts.read,
+                );
+                // The slot is released once the substream ended.
+                self.inbound_frames.push(
+                    frames
+                        .map(move |frame| {
+                            let _slot = &slot;
+                            frame
+                        })
+                        .boxed(),
+                );
+            }
+            protocol::Inbound::Stream(socket, slot) => {
+                let stream = RawStream::new(socket, self.streams.clone(), Some(slot));
+                self.queued_events.push_back(ConnectionHandlerEvent::Custom(
+                    HandlerEvent::InboundStream(stream),
+                ));
+            }
+        }
+    }
+
+    fn inject_fully_negotiated_outbound(&mut self, output: protocol::Success, info: OpenInfo) {
+        let id = match info {
+            OpenInfo::Outbound(id) => id,
+            OpenInfo::Stream(sender) => {
+                if let protocol::Success::Stream(socket) = output {
+                    let _ = sender.send(Ok(RawStream::new(socket, self.streams.clone(), None)));
+                }
+                return;
+            }
+            OpenInfo::Persistent => {
+                if let protocol::Success::Persistent(socket, compression) = output {
+                    self.persistent = Persistent::Idle(socket);
+                    self.persistent_compression = compression;
+                }
+                return;
+            }
+        };
+        self.outbound_in_flight -= 1;
+        self.counters.outbound.fetch_sub(1, Ordering::Relaxed);
+        self.pending_outbound.remove(&id);
+        let event = match (id, output) {
+            (OutboundId::Request(id), protocol::Success::Response(data)) => {
+                HandlerEvent::Response(id, data)
+            }
+            (OutboundId::Request(id), _) => {
+                unreachable!("request {} completed without a response", id)
+            }
+            (OutboundId::Message(id), protocol::Success::Acked) => {
+                HandlerEvent::SendSucceeded(id, true)
+            }
+            (OutboundId::Message(id), _) => HandlerEvent::SendSucceeded(id, false),
+        };
         self.queued_events
-            .push_back(ConnectionHandlerEvent::Custom(protocol::MsgContent {
-                data: output,
-            }));
+            .push_back(ConnectionHandlerEvent::Custom(event));
     }
 
-    fn inject_fully_negotiated_outbound(&mut self, _output: protocol::Success, (): ()) {
+    fn inject_event(&mut self, event: HandlerIn) {
+        let (id, upgrade) = match event {
+            HandlerIn::Send { id, envelope, data }
+                if self.config.persistent_stream
+                    && !matches!(self.persistent, Persistent::Unsupported) =>
+            {
+                self.pending_outbound.insert(OutboundId::Message(id));
+                self.persistent_queue.push_back((id, envelope, data));
+                self.counters.waiting.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            HandlerIn::Send { id, envelope, data } => (
+                OutboundId::Message(id),
+                protocol::MsgOutbound::Message {
+                    envelope,
+                    data,
+                    protocols: self.protocols.clone(),
+                    timeouts: self.config.timeouts,
+                },
+            ),
+            HandlerIn::Pin(pinned) => {
+                self.pinned = pinned;
+                return;
+            }
+            HandlerIn::OpenStream(sender) => {
+                let upgrade = protocol::MsgOutbound::Stream {
+                    protocols: self.protocols.clone(),
+                };
+                let protocol = SubstreamProtocol::new(upgrade, OpenInfo::Stream(sender))
+                    .with_timeout(self.config.negotiation_timeout);
+                self.queued_events
+                    .push_back(ConnectionHandlerEvent::OutboundSubstreamRequest { protocol });
+                return;
+            }
+            HandlerIn::Request { id, data } => (
+                OutboundId::Request(id),
+                protocol::MsgOutbound::Request {
+                    data,
+                    max_response_size: self.config.max_message_size,
+                    protocols: self.protocols.clone(),
+                    timeouts: self.config.timeouts,
+                },
+            ),
+        };
+        self.pending_outbound.insert(id);
+        self.waiting_outbound.push_back((id, upgrade));
+        self.counters.waiting.fetch_add(1, Ordering::Relaxed);
     }
 
-    fn inject_event(&mut self, msg: protocol::MsgContent) {
-        //println!("handler inject event ");
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: OpenInfo,
+        error: ConnectionHandlerUpgrErr<std::io::Error>,
+    ) {
+        let error = match error {
+            ConnectionHandlerUpgrErr::Timeout | ConnectionHandlerUpgrErr::Timer => {
+                SendError::Timeout
+            }
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(NegotiationError::Failed)) => {
+                SendError::UnsupportedProtocols
+            }
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(
+                NegotiationError::ProtocolError(e),
+            )) => SendError::Io(e.into()),
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(e))
+                if e.kind() == io::ErrorKind::TimedOut =>
+            {
+                SendError::Timeout
+            }
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(e)) => SendError::Io(e),
+        };
+        let id = match info {
+            OpenInfo::Outbound(id) => id,
+            OpenInfo::Stream(sender) => {
+                let _ = sender.send(Err(error));
+                return;
+            }
+            OpenInfo::Persistent => {
+                if let SendError::UnsupportedProtocols = error {
+                    self.fall_back_to_substreams();
+                    return;
+                }
+                // Fail the oldest message so a broken remote can't stall the queue;
+                // the substream is re-opened for the rest.
+                self.persistent = Persistent::Closed;
+                if let Some((id, ..)) = self.persistent_queue.pop_front() {
+                    self.counters.waiting.fetch_sub(1, Ordering::Relaxed);
+                    self.pending_outbound.remove(&OutboundId::Message(id));
+                    self.queued_events.push_back(ConnectionHandlerEvent::Custom(
+                        HandlerEvent::SendFailed(id, error),
+                    ));
+                }
+                return;
+            }
+        };
+        self.outbound_in_flight -= 1;
+        self.counters.outbound.fetch_sub(1, Ordering::Relaxed);
+        self.pending_outbound.remove(&id);
+        let event = match id {
+            OutboundId::Message(id) => HandlerEvent::SendFailed(id, error),
+            OutboundId::Request(id) => HandlerEvent::RequestFailed(id, error),
+        };
         self.queued_events
-            .push_back(ConnectionHandlerEvent::OutboundSubstreamRequest {
-                protocol: SubstreamProtocol::new(msg, ()),
-            });
+            .push_back(ConnectionHandlerEvent::Custom(event));
     }
 
-    fn inject_dial_upgrade_error(
+    fn inject_listen_upgrade_error(
         &mut self,
-        _info: (),
-        _error: ConnectionHandlerUpgrErr<std::io::Error>,
+        (): (),
+        error: ConnectionHandlerUpgrErr<protocol::RecvError>,
     ) {
+        // The remote asked for a protocol we don't speak, or we refused the
+        // substream, nothing was read.
+        if let ConnectionHandlerUpgrErr::Upgrade(
+            UpgradeError::Select(_) | UpgradeError::Apply(protocol::RecvError::Refused),
+        ) = error
+        {
+            return;
+        }
+        self.queued_events
+            .push_back(ConnectionHandlerEvent::Custom(HandlerEvent::InboundFailed(
+                error,
+            )));
     }
 
     fn connection_keep_alive(&self) -> KeepAlive {
-        KeepAlive::Yes
+        if self.pinned {
+            return KeepAlive::Yes;
+        }
+        self.keep_alive
     }
 
     fn poll(
         &mut self,
-        _cx: &mut Context<'_>,
-    ) -> Poll<ConnectionHandlerEvent<protocol::MsgContent, (), protocol::MsgContent, Self::Error>>
+        cx: &mut Context<'_>,
+    ) -> Poll<ConnectionHandlerEvent<protocol::MsgOutbound, OpenInfo, HandlerEvent, Self::Error>>
     {
-        if let Some(msg) = self.queued_events.pop_back() {
+        while let Poll::Ready(Some((id, result))) = self.pending_responses.poll_next_unpin(cx) {
+            self.pending_inbound.remove(&id);
+            let event = match result {
+                Ok(()) => HandlerEvent::ResponseSent(id),
+                Err(e) => HandlerEvent::ResponseFailed(id, e),
+            };
+            self.queued_events
+                .push_back(ConnectionHandlerEvent::Custom(event));
+        }
+
+        while let Poll::Ready(Some(frame)) = self.inbound_frames.poll_next_unpin(cx) {
+            // Persistent substreams carry 2.0.0 frames, but no acks.
+            let max_size = self.config.max_message_size;
+            let event = match frame.and_then(|f| protocol::MsgContent::from_frame(f, max_size)) {
+                Ok(content) => HandlerEvent::Received(content, protocol::Version::V2_0),
+                Err(e) => HandlerEvent::InboundFailed(ConnectionHandlerUpgrErr::Upgrade(
+                    UpgradeError::Apply(e),
+                )),
+            };
+            self.queued_events
+                .push_back(ConnectionHandlerEvent::Custom(event));
+        }
+
+        if self.is_busy() {
+            self.keep_alive = KeepAlive::Yes;
+        } else if self.keep_alive.is_yes() {
+            self.keep_alive = KeepAlive::Until(Instant::now() + self.config.idle_timeout);
+        }
+
+        if let Some(msg) = self.queued_events.pop_front() {
             return Poll::Ready(msg);
         }
 
+        if let Some(event) = self.poll_persistent(cx) {
+            return Poll::Ready(event);
+        }
+
+        if self.outbound_in_flight < self.config.outbound_limit() {
+            if let Some((id, upgrade)) = self.waiting_outbound.pop_front() {
+                self.outbound_in_flight += 1;
+                self.counters.waiting.fetch_sub(1, Ordering::Relaxed);
+                self.counters.outbound.fetch_add(1, Ordering::Relaxed);
+                let timeout = match id {
+                    OutboundId::Message(_) => self.config.message_timeout(),
+                    OutboundId::Request(_) => self.config.request_timeout,
+                };
+                let protocol =
+                    SubstreamProtocol::new(upgrade, OpenInfo::Outbound(id)).with_timeout(timeout);
+                return Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { protocol });
+            }
+        }
+
         Poll::Pending
     }
 }
+
+impl Drop for Handler {
+    fn drop(&mut self) {
+        self.counters
+            .outbound
+            .fetch_sub(self.outbound_in_flight, Ordering::Relaxed);
+        self.counters.waiting.fetch_sub(
+            self.waiting_outbound.len() + self.persistent_queue.len(),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+
+    fn handler(config: Config) -> Handler {
+        Handler::new(config, Default::default(), Default::default())
+    }
+
+    fn send(handler: &mut Handler, id: u64) {
+        handler.inject_event(HandlerIn::Send {
+            id: MessageId(id),
+            envelope: Bytes::new(),
+            data: Bytes::from_static(b"msg"),
+        });
+    }
+
+    fn poll(handler: &mut Handler) -> Poll<<Handler as ConnectionHandler>::OutEvent> {
+        handler
+            .poll(&mut Context::from_waker(noop_waker_ref()))
+            .map(|event| match event {
+                ConnectionHandlerEvent::Custom(event) => event,
+                _ => panic!("unexpected event"),
+            })
+    }
+
+    fn poll_open(handler: &mut Handler) -> Option<OutboundId> {
+        match handler.poll(&mut Context::from_waker(noop_waker_ref())) {
+            Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { protocol }) => {
+                match protocol.into_upgrade().1 {
+                    OpenInfo::Outbound(id) => Some(id),
+                    _ => panic!("unexpected open info"),
+                }
+            }
+            Poll::Ready(_) => panic!("unexpected event"),
+            Poll::Pending => None,
+        }
+    }
+
+    #[test]
+    fn opens_substreams_in_send_order() {
+        let mut handler = handler(Config::new());
+        for id in 0..5 {
+            send(&mut handler, id);
+        }
+        for id in 0..5 {
+            assert_eq!(
+                poll_open(&mut handler),
+                Some(OutboundId::Message(MessageId(id)))
+            );
+        }
+        assert_eq!(poll_open(&mut handler), None);
+    }
+
+    #[test]
+    fn sequential_outbound_opens_one_substream_at_a_time() {
+        let mut handler = handler(Config::new().with_sequential_outbound(true));
+        for id in 0..3 {
+            send(&mut handler, id);
+        }
+        for id in 0..3 {
+            let id = OutboundId::Message(MessageId(id));
+            assert_eq!(poll_open(&mut handler), Some(id));
+            assert_eq!(poll_open(&mut handler), None);
+            handler.inject_dial_upgrade_error(
+                OpenInfo::Outbound(id),
+                ConnectionHandlerUpgrErr::Timeout,
+            );
+            assert!(matches!(
+                poll(&mut handler),
+                Poll::Ready(HandlerEvent::SendFailed(failed, SendError::Timeout))
+                    if OutboundId::Message(failed) == id
+            ));
+        }
+    }
+
+    #[test]
+    fn reports_events_in_order() {
+        let mut handler = handler(Config::new());
+        for id in 0..5 {
+            send(&mut handler, id);
+            poll_open(&mut handler);
+        }
+        for id in 0..5 {
+            handler.inject_dial_upgrade_error(
+                OpenInfo::Outbound(OutboundId::Message(MessageId(id))),
+                ConnectionHandlerUpgrErr::Timeout,
+            );
+        }
+        for id in 0..5 {
+            assert!(matches!(
+                poll(&mut handler),
+                Poll::Ready(HandlerEvent::SendFailed(failed, _)) if failed == MessageId(id)
+            ));
+        }
+    }
+}
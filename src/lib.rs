@@ -1,15 +1,53 @@
+mod codec;
+mod compression;
+mod control;
+mod envelope;
+pub mod file;
 mod handler;
 mod protocol;
+mod sealed;
+mod stream;
 
-pub use protocol::MsgContent;
+#[cfg(feature = "bincode")]
+pub use codec::BincodeCodec;
+#[cfg(feature = "cbor")]
+pub use codec::CborCodec;
+#[cfg(feature = "json")]
+pub use codec::JsonCodec;
+pub use codec::{Codec, RawCodec};
+pub use compression::Compression;
+pub use control::{Closed, Control, TrySendError};
+pub use envelope::{Envelope, Metadata};
+pub use protocol::{MsgContent, Version};
+pub use sealed::SealError;
+pub use stream::{IncomingStreams, RawStream};
 
-use handler::Handler;
-pub use handler::Success;
-use libp2p::core::{connection::ConnectionId, PeerId};
-use libp2p::swarm::{NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters};
+use bytes::Bytes;
+use control::{Buffers, Command};
+use futures::channel::{mpsc, oneshot};
+use futures::{Future, FutureExt, StreamExt};
+use futures_timer::Delay;
+pub use handler::{Config, Stats, Success};
+use handler::{Counters, Handler, HandlerEvent, HandlerIn, OutboundId};
+use libp2p::core::identity::error::SigningError;
+use libp2p::core::multiaddr::Protocol;
+use libp2p::core::upgrade::UpgradeError;
+use libp2p::core::{connection::ConnectionId, ConnectedPoint, Multiaddr, PeerId};
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+use libp2p::swarm::{
+    ConnectionHandlerUpgrErr, DialError, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler,
+    PollParameters,
+};
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, HashSet, VecDeque},
+    error, fmt, io, mem,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     task::{Context, Poll},
+    time::Instant,
 };
 
 #[deprecated(
@@ -23,74 +61,1443 @@ use std::io::Error;
 /// The result of an inbound or outbound stream.
 pub type Result = std::result::Result<Success, Error>;
 
-/// A [`NetworkBehaviour`] that responds to inbound pings and
-/// periodically sends outbound pings on every established connection.
+/// A [`NetworkBehaviour`] that sends length-prefixed messages to peers and
+/// reports the messages it receives.
+///
+/// # Ordering
+///
+/// Events are yielded to the swarm in the order they happened, and messages to
+/// a peer are handed to its connection in the order [`Behaviour::send`] was
+/// called. Whether the remote also reads them in that order depends on
+/// [`Config::with_sequential_outbound`].
 ///
-/// See the crate root documentation for more information.
-pub struct Behaviour {
+/// # Connections
+///
+/// Messages to a peer are sent on its oldest direct connection, falling back
+/// to a relayed one, e.g. until DCUtR established a direct connection.
+/// With [`Config::with_sequential_outbound`] they stay on the connection the
+/// previous ones were sent on until it completed them, so switching to a new
+/// connection doesn't reorder them.
+/// [`Behaviour::send_on`] picks a connection explicitly.
+pub struct Behaviour<C: Codec = RawCodec> {
+    config: Config,
+    codec: C,
     /// Queue of events to yield to the swarm.
-    events: VecDeque<NetworkBehaviourAction<Event, Handler>>,
+    events: VecDeque<NetworkBehaviourAction<Event<C::Message>, Handler>>,
+    /// ID assigned to the next message passed to [`Behaviour::send`].
+    next_message_id: Arc<AtomicU64>,
+    /// Established connections of every connected peer, oldest first.
+    connections: HashMap<PeerId, Vec<Connection>>,
+    /// Messages whose outcome hasn't been reported yet, with the connection
+    /// they were handed to, if any yet.
+    outstanding: HashMap<MessageId, (PeerId, Option<ConnectionId>)>,
+    /// With sequential outbound, the connection each peer's messages stick
+    /// to and how many of them it hasn't completed yet.
+    sending_on: HashMap<PeerId, (ConnectionId, usize)>,
+    /// Source of request IDs, shared with the handlers for inbound requests.
+    next_request_id: Arc<AtomicU64>,
+    /// Outbound requests whose response hasn't been reported yet.
+    outstanding_requests: HashMap<RequestId, PeerId>,
+    /// Messages and requests for peers we are dialing, oldest first.
+    pending: HashMap<PeerId, VecDeque<PendingOutbound>>,
+    /// Fires when the oldest entry in `pending` expires.
+    pending_timer: Option<Delay>,
+    /// Peers whose connections are kept alive even when idle.
+    pinned: HashSet<PeerId>,
+    /// Substream counters of all connections.
+    counters: Arc<Counters>,
+    /// Unfinished messages per peer, shared with the [`Control`]s.
+    buffers: Arc<Mutex<Buffers>>,
+    /// Messages sent through a [`Control`].
+    commands: mpsc::UnboundedReceiver<Command<C::Message>>,
+    command_sender: mpsc::UnboundedSender<Command<C::Message>>,
+    /// Where raw streams opened by remotes go, see [`Behaviour::incoming_streams`].
+    stream_listener: Option<mpsc::UnboundedSender<(PeerId, RawStream)>>,
+    /// File transfers to and from all peers.
+    files: file::Transfers,
 }
 
-/// Event generated by the `Ping` network behaviour.
+/// An established connection to a peer.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    pub id: ConnectionId,
+    pub endpoint: ConnectedPoint,
+    /// Whether the connection runs over a relay circuit.
+    pub relayed: bool,
+}
+
+impl Connection {
+    fn new(id: ConnectionId, endpoint: ConnectedPoint) -> Self {
+        let relayed = match &endpoint {
+            ConnectedPoint::Dialer { address, .. } => is_relayed(address),
+            ConnectedPoint::Listener {
+                local_addr,
+                send_back_addr,
+            } => is_relayed(local_addr) || is_relayed(send_back_addr),
+        };
+        Connection {
+            id,
+            endpoint,
+            relayed,
+        }
+    }
+}
+
+fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+/// The connection messages to a peer are sent on: the oldest direct one, or
+/// the oldest relayed one if there is no direct connection.
+fn preferred(connections: &[Connection]) -> Option<&Connection> {
+    connections
+        .iter()
+        .find(|c| !c.relayed)
+        .or_else(|| connections.first())
+}
+
+/// A message or request waiting for a connection to its peer.
+struct PendingOutbound {
+    id: OutboundId,
+    event: HandlerIn,
+    deadline: Instant,
+}
+
+/// Identifies a message sent with [`Behaviour::send`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId(u64);
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Identifies an inbound or outbound request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(u64);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Answers an inbound request, see [`Behaviour::respond`].
+///
+/// Dropping the channel without responding fails the request with
+/// [`ResponseError::Omission`].
 #[derive(Debug)]
-pub struct Event {
-    /// The peer ID of the remote.
-    pub peer: PeerId,
-    /// The result of an inbound or outbound ping.
-    pub result: protocol::MsgContent,
+pub struct ResponseChannel {
+    sender: oneshot::Sender<Vec<u8>>,
+}
+
+impl ResponseChannel {
+    /// Whether the substream is still waiting for the response.
+    pub fn is_open(&self) -> bool {
+        !self.sender.is_canceled()
+    }
+}
+
+/// Event generated by the [`Behaviour`], `M` is the [`Codec::Message`].
+#[derive(Debug)]
+pub enum Event<M = Vec<u8>> {
+    /// A message was received from a remote.
+    Received {
+        /// The peer ID of the remote.
+        peer: PeerId,
+        /// The received message.
+        message: M,
+        /// The protocol version the message was received with.
+        version: Version,
+        /// The message's metadata, unless the protocol version has no envelopes.
+        envelope: Option<Envelope>,
+        /// Who wrote the message: the signer if the envelope is signed,
+        /// otherwise `peer`.
+        author: PeerId,
+        /// Whether `author` is proven by a valid signature, see
+        /// [`Config::with_signing`].
+        verified: bool,
+    },
+    /// A message sent with [`Behaviour::send`] reached the remote.
+    SendSucceeded {
+        id: MessageId,
+        peer: PeerId,
+        /// Whether the remote acknowledged the message, as it does with
+        /// [`Version::V1_1`] and later. Otherwise the message was only fully
+        /// written, with [`Version::V1_0`] or on the persistent substream.
+        acked: bool,
+    },
+    /// A message sent with [`Behaviour::send`] could not be delivered.
+    SendFailed {
+        id: MessageId,
+        peer: PeerId,
+        error: SendError,
+    },
+    /// An inbound message was rejected or could not be read.
+    InboundFailed(InboundError),
+    /// An inbound message was dropped because its signature doesn't match
+    /// the public key of the `author` it claims.
+    InvalidSignature { peer: PeerId, author: PeerId },
+    /// Messages to `peer` now go over the direct `connection` instead of a relay.
+    DirectConnectionPreferred {
+        peer: PeerId,
+        connection: ConnectionId,
+    },
+    /// A peer sent a request, to be answered with [`Behaviour::respond`].
+    Request {
+        peer: PeerId,
+        request_id: RequestId,
+        data: M,
+        channel: ResponseChannel,
+    },
+    /// A peer answered a request sent with [`Behaviour::request`].
+    Response {
+        peer: PeerId,
+        request_id: RequestId,
+        data: M,
+    },
+    /// A request sent with [`Behaviour::request`] got no response.
+    RequestFailed {
+        peer: PeerId,
+        request_id: RequestId,
+        error: SendError,
+    },
+    /// The response to an inbound request was written.
+    ResponseSent { peer: PeerId, request_id: RequestId },
+    /// The response to an inbound request could not be written.
+    ResponseFailed {
+        peer: PeerId,
+        request_id: RequestId,
+        error: ResponseError,
+    },
+    /// A file transfer started with [`Behaviour::send_file`], or offered by
+    /// a peer, made progress.
+    File(file::Event),
+}
+
+/// Why the response to an inbound request wasn't sent.
+#[derive(Debug)]
+pub enum ResponseError {
+    /// The [`ResponseChannel`] was dropped without responding.
+    Omission,
+    /// The application didn't respond within [`Config::with_request_timeout`],
+    /// or writing the response exceeded [`Config::with_write_timeout`].
+    Timeout,
+    /// The connection closed before the response was sent.
+    ConnectionClosed,
+    /// I/O error while writing the response.
+    Io(io::Error),
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResponseError::Omission => write!(f, "response channel dropped"),
+            ResponseError::Timeout => write!(f, "timeout"),
+            ResponseError::ConnectionClosed => write!(f, "connection closed"),
+            ResponseError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl error::Error for ResponseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ResponseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Why an inbound message was not delivered as [`Event::Received`].
+#[derive(Debug)]
+pub enum InboundError {
+    /// The peer announced a message larger than [`Config::with_max_message_size`].
+    MessageTooLarge { peer: PeerId, announced_len: usize },
+    /// The peer didn't finish sending the message in time.
+    Timeout { peer: PeerId },
+    /// I/O error while reading the message.
+    Io { peer: PeerId, error: io::Error },
+    /// The message or request couldn't be decoded.
+    ///
+    /// An undecodable request is not answered, so the remote sees it fail.
+    Codec { peer: PeerId, error: BoxError },
+    /// The payload was sealed, but couldn't be decrypted, see
+    /// [`Config::with_decryption`].
+    Decryption { peer: PeerId, error: SealError },
+}
+
+impl InboundError {
+    fn new(peer: PeerId, error: ConnectionHandlerUpgrErr<protocol::RecvError>) -> Self {
+        match error {
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(
+                protocol::RecvError::MessageTooLarge { announced_len },
+            )) => InboundError::MessageTooLarge {
+                peer,
+                announced_len,
+            },
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(protocol::RecvError::Io(
+                error,
+            ))) => InboundError::Io { peer, error },
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(
+                protocol::RecvError::Refused,
+            )) => InboundError::Io {
+                peer,
+                error: io::ErrorKind::ConnectionRefused.into(),
+            },
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(
+                protocol::RecvError::InvalidEnvelope(e),
+            )) => InboundError::Io {
+                peer,
+                error: io::Error::new(io::ErrorKind::InvalidData, e),
+            },
+            ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Select(e)) => InboundError::Io {
+                peer,
+                error: e.into(),
+            },
+            ConnectionHandlerUpgrErr::Timeout
+            | ConnectionHandlerUpgrErr::Timer
+            | ConnectionHandlerUpgrErr::Upgrade(UpgradeError::Apply(
+                protocol::RecvError::Timeout,
+            )) => InboundError::Timeout { peer },
+        }
+    }
+
+    /// The peer the inbound message came from.
+    pub fn peer(&self) -> &PeerId {
+        match self {
+            InboundError::MessageTooLarge { peer, .. }
+            | InboundError::Timeout { peer }
+            | InboundError::Io { peer, .. }
+            | InboundError::Codec { peer, .. }
+            | InboundError::Decryption { peer, .. } => peer,
+        }
+    }
+}
+
+impl fmt::Display for InboundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InboundError::MessageTooLarge {
+                peer,
+                announced_len,
+            } => write!(
+                f,
+                "{} announced a message of {} bytes, which is too large",
+                peer, announced_len
+            ),
+            InboundError::Timeout { peer } => write!(f, "timeout reading message from {}", peer),
+            InboundError::Io { peer, error } => {
+                write!(f, "I/O error reading message from {}: {}", peer, error)
+            }
+            InboundError::Codec { peer, error } => {
+                write!(f, "failed to decode message from {}: {}", peer, error)
+            }
+            InboundError::Decryption { peer, error } => {
+                write!(f, "failed to decrypt message from {}: {}", peer, error)
+            }
+        }
+    }
+}
+
+impl error::Error for InboundError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            InboundError::Io { error, .. } => Some(error),
+            InboundError::Codec { error, .. } => Some(&**error),
+            InboundError::Decryption { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Error returned by a [`Codec`].
+pub type BoxError = Box<dyn error::Error + Send + Sync>;
+
+/// Why a message sent with [`Behaviour::send`] or a request failed.
+#[derive(Debug)]
+pub enum SendError {
+    /// There was no connection to the peer and queueing is disabled.
+    NotConnected,
+    /// There was no connection to the peer and its pending queue was full.
+    QueueFull,
+    /// The peer's outbound buffer was full, see [`Config::with_outbound_buffer`].
+    BufferFull,
+    /// Dialing the peer failed.
+    DialFailed,
+    /// No connection to the peer was established within the pending TTL.
+    Expired,
+    /// The connection closed before the message was sent.
+    ConnectionClosed,
+    /// The remote supports none of our protocols.
+    UnsupportedProtocols,
+    /// The message couldn't be encoded, or the response couldn't be decoded.
+    Codec(BoxError),
+    /// The envelope couldn't be signed, see [`Config::with_signing`].
+    Signing(SigningError),
+    /// The payload couldn't be sealed to the peer, see [`Metadata::with_sealed`].
+    Sealing(SealError),
+    /// Negotiating the substream, writing the payload or reading the ack or
+    /// response timed out, see [`Config::with_write_timeout`].
+    Timeout,
+    /// I/O error while negotiating or writing the substream.
+    Io(io::Error),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::NotConnected => write!(f, "peer is not connected"),
+            SendError::QueueFull => write!(f, "pending queue is full"),
+            SendError::BufferFull => write!(f, "outbound buffer is full"),
+            SendError::DialFailed => write!(f, "failed to dial peer"),
+            SendError::Expired => write!(f, "peer did not connect in time"),
+            SendError::ConnectionClosed => write!(f, "connection closed"),
+            SendError::UnsupportedProtocols => write!(f, "remote supports no msg protocol"),
+            SendError::Codec(e) => write!(f, "codec error: {}", e),
+            SendError::Signing(e) => write!(f, "signing error: {}", e),
+            SendError::Sealing(e) => write!(f, "{}", e),
+            SendError::Timeout => write!(f, "timeout"),
+            SendError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl error::Error for SendError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SendError::Io(e) => Some(e),
+            SendError::Codec(e) => Some(&**e),
+            SendError::Signing(e) => Some(e),
+            SendError::Sealing(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
 impl Behaviour {
-    /// Creates a new `Ping` network behaviour with the given configuration.
-    pub fn new() -> Self {
+    /// Creates a new network behaviour exchanging raw bytes.
+    pub fn new(config: Config) -> Self {
+        Self::with_codec(config, RawCodec)
+    }
+}
+
+impl<C: Codec> Behaviour<C> {
+    /// Creates a new network behaviour exchanging messages encoded with `codec`.
+    pub fn with_codec(config: Config, codec: C) -> Self {
+        let (command_sender, commands) = mpsc::unbounded();
         Self {
+            buffers: Arc::new(Mutex::new(Buffers::new(config.outbound_buffer()))),
+            commands,
+            command_sender,
+            stream_listener: None,
+            files: file::Transfers::new(&config),
+            config,
+            codec,
             events: VecDeque::new(),
+            next_message_id: Arc::new(AtomicU64::new(0)),
+            connections: HashMap::new(),
+            outstanding: HashMap::new(),
+            sending_on: HashMap::new(),
+            next_request_id: Arc::new(AtomicU64::new(0)),
+            outstanding_requests: HashMap::new(),
+            pending: HashMap::new(),
+            pending_timer: None,
+            pinned: HashSet::new(),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// Sends `message` to `peer_id`.
+    ///
+    /// If the peer isn't connected the message is queued and the peer dialed,
+    /// see [`Config::with_pending_queue`]. If the peer's outbound buffer is
+    /// full the message fails with [`SendError::BufferFull`]; use a
+    /// [`Control`] to wait for room instead.
+    ///
+    /// The outcome is reported as [`Event::SendSucceeded`] or
+    /// [`Event::SendFailed`] carrying the returned ID.
+    pub fn send(&mut self, message: impl Into<C::Message>, peer_id: PeerId) -> MessageId {
+        self.send_with(message, peer_id, Metadata::default())
+    }
+
+    /// Like [`Behaviour::send`], with `metadata` in the message's envelope.
+    ///
+    /// Peers that only speak protocol versions without envelopes, see
+    /// [`Config::with_versions`], receive the message without its metadata.
+    pub fn send_with(
+        &mut self,
+        message: impl Into<C::Message>,
+        peer_id: PeerId,
+        metadata: Metadata,
+    ) -> MessageId {
+        match self.codec.encode(&message.into()) {
+            Ok(data) => self.send_encoded(peer_id, data.into(), &metadata),
+            Err(e) => self.send_failed(peer_id, SendError::Codec(Box::new(e))),
+        }
+    }
+
+    /// Sends `message` to each of `peers`, returning the ID of every copy.
+    ///
+    /// The message is encoded once and the bytes are shared between all
+    /// recipients. Every copy is reported like a message passed to
+    /// [`Behaviour::send`].
+    pub fn send_many(
+        &mut self,
+        peers: impl IntoIterator<Item = PeerId>,
+        message: impl Into<C::Message>,
+    ) -> Vec<(PeerId, MessageId)> {
+        let encoded = self.codec.encode(&message.into()).map(Bytes::from);
+        let metadata = Metadata::default();
+        peers
+            .into_iter()
+            .map(|peer| {
+                let id = match &encoded {
+                    Ok(data) => self.send_encoded(peer, Bytes::clone(data), &metadata),
+                    Err(e) => self.send_failed(peer, SendError::Codec(e.to_string().into())),
+                };
+                (peer, id)
+            })
+            .collect()
+    }
+
+    /// Sends `message` to every connected peer, see [`Behaviour::send_many`].
+    pub fn broadcast(&mut self, message: impl Into<C::Message>) -> Vec<(PeerId, MessageId)> {
+        let peers: Vec<PeerId> = self.connections.keys().copied().collect();
+        self.send_many(peers, message)
+    }
+
+    /// Sends `message` on a specific connection to `peer_id`.
+    ///
+    /// Unlike [`Behaviour::send`], the message is not queued if the
+    /// connection doesn't exist but fails with [`SendError::NotConnected`].
+    pub fn send_on(
+        &mut self,
+        peer_id: PeerId,
+        connection: ConnectionId,
+        message: impl Into<C::Message>,
+    ) -> MessageId {
+        let data = match self.codec.encode(&message.into()) {
+            Ok(data) => Bytes::from(data),
+            Err(e) => return self.send_failed(peer_id, SendError::Codec(Box::new(e))),
+        };
+        let exists = self
+            .connections
+            .get(&peer_id)
+            .is_some_and(|cs| cs.iter().any(|c| c.id == connection));
+        if !exists {
+            return self.send_failed(peer_id, SendError::NotConnected);
+        }
+        if !self.lock_buffers().try_reserve(peer_id) {
+            return self.send_failed(peer_id, SendError::BufferFull);
+        }
+        let id = self.next_message_id();
+        self.track(id, peer_id);
+        let (envelope, data) = match self.encode_envelope(id, peer_id, &Metadata::default(), data) {
+            Ok(parts) => parts,
+            Err(error) => {
+                self.fail(peer_id, OutboundId::Message(id), error);
+                return id;
+            }
+        };
+        self.notify_connection(peer_id, connection, HandlerIn::Send { id, envelope, data });
+        id
+    }
+
+    /// Keeps the connections to `peer_id` alive even when idle, see
+    /// [`Config::with_idle_timeout`].
+    pub fn pin(&mut self, peer_id: PeerId) {
+        if self.pinned.insert(peer_id) {
+            self.notify_pin(peer_id, true);
+        }
+    }
+
+    /// Undoes [`Behaviour::pin`].
+    pub fn unpin(&mut self, peer_id: &PeerId) {
+        if self.pinned.remove(peer_id) {
+            self.notify_pin(*peer_id, false);
+        }
+    }
+
+    fn notify_pin(&mut self, peer_id: PeerId, pinned: bool) {
+        for connection in self.connections.get(&peer_id).into_iter().flatten() {
+            self.events
+                .push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id,
+                    handler: NotifyHandler::One(connection.id),
+                    event: HandlerIn::Pin(pinned),
+                });
+        }
+    }
+
+    /// Counts the substreams of all connections, for diagnostics.
+    pub fn stats(&self) -> Stats {
+        self.counters.stats()
+    }
+
+    /// The established connections to `peer_id`, oldest first.
+    pub fn connections(&self, peer_id: &PeerId) -> &[Connection] {
+        self.connections
+            .get(peer_id)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Returns a handle for sending messages from other tasks.
+    ///
+    /// Messages sent through it are handed to connections when the behaviour
+    /// is polled, in the order they were sent.
+    pub fn control(&self) -> Control<C::Message> {
+        Control::new(
+            self.command_sender.clone(),
+            self.buffers.clone(),
+            self.next_message_id.clone(),
+        )
+    }
+
+    /// Opens a raw stream to the connected peer `peer_id`.
+    ///
+    /// The stream is negotiated as `<prefix>/stream/1.0.0` on the preferred
+    /// connection and the bytes written to it are not framed in any way. The
+    /// returned future resolves once the remote accepted the stream; it is
+    /// driven by polling the swarm.
+    pub fn open_stream(
+        &mut self,
+        peer_id: PeerId,
+    ) -> impl Future<Output = std::result::Result<RawStream, SendError>> + Send + 'static {
+        let (sender, receiver) = oneshot::channel();
+        self.on_open_stream(peer_id, sender);
+        receiver.map(|result| result.unwrap_or(Err(SendError::ConnectionClosed)))
+    }
+
+    /// Returns the raw streams opened by remote peers.
+    ///
+    /// Only the listener returned last receives streams. Without a listener,
+    /// inbound streams are dropped right away. Inbound streams count against
+    /// [`Config::with_max_inbound_streams`] until dropped.
+    pub fn incoming_streams(&mut self) -> IncomingStreams {
+        let (sender, receiver) = mpsc::unbounded();
+        self.stream_listener = Some(sender);
+        IncomingStreams::new(receiver)
+    }
+
+    /// Offers the file at `path` to `peer_id`, see [`file`].
+    ///
+    /// The file is sent once the peer accepted it, and the outcome is
+    /// reported as [`file::Event::TransferCompleted`] or
+    /// [`file::Event::TransferFailed`] carrying the returned ID. Fails if the
+    /// file can't be opened; it is hashed and offered in the background.
+    /// Transfers require [`Version::V2_0`] on both ends.
+    ///
+    /// A transfer interrupted by a lost connection resumes once connected to
+    /// the peer again, from where the peer stopped writing the file.
+    pub fn send_file(
+        &mut self,
+        peer_id: PeerId,
+        path: impl AsRef<Path>,
+    ) -> io::Result<file::TransferId> {
+        self.files.send_file(peer_id, path.as_ref())
+    }
+
+    /// Cancels the file transfer `id`, sending or receiving, and tells the
+    /// peer if connected. Returns whether there was such a transfer.
+    ///
+    /// Any partial file is removed, and the transfer is reported as
+    /// [`file::Event::TransferFailed`] with [`file::FailureReason::Cancelled`]
+    /// here and [`file::FailureReason::CancelledByPeer`] on the peer.
+    pub fn cancel_transfer(&mut self, id: file::TransferId) -> bool {
+        self.files.cancel(id)
+    }
+
+    /// Sends the messages of file transfers that are ready. Those to peers
+    /// whose outbound buffer is full wait until it has room, and those
+    /// ending a transfer are dropped if the peer isn't connected.
+    fn send_file_messages(&mut self) {
+        let mut blocked = Vec::new();
+        while let Some((peer_id, message)) = self.files.next_message() {
+            if message.ends_transfer() && !self.connections.contains_key(&peer_id) {
+                log::debug!("Dropping {:?} to disconnected peer {}", message, peer_id);
+                continue;
+            }
+            if !self.lock_buffers().try_reserve(peer_id) {
+                blocked.push((peer_id, message));
+                continue;
+            }
+            let id = self.next_message_id();
+            self.files.track(id, peer_id, &message);
+            self.send_reserved(id, peer_id, message.encode(), &Metadata::file());
+        }
+        self.files.requeue(blocked);
+    }
+
+    fn on_open_stream(&mut self, peer_id: PeerId, sender: handler::StreamSender) {
+        if self.connections.contains_key(&peer_id) {
+            self.notify_handler(peer_id, HandlerIn::OpenStream(sender));
+        } else {
+            let _ = sender.send(Err(SendError::NotConnected));
         }
     }
 
-    pub fn send(&mut self, data: impl Into<Vec<u8>>, peer_id: PeerId) {
+    fn next_message_id(&mut self) -> MessageId {
+        MessageId(self.next_message_id.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn lock_buffers(&self) -> std::sync::MutexGuard<'_, Buffers> {
+        self.buffers.lock().expect("not poisoned")
+    }
+
+    /// Records a message whose buffer slot is reserved as outstanding.
+    fn track(&mut self, id: MessageId, peer_id: PeerId) {
+        self.outstanding.insert(id, (peer_id, None));
+    }
+
+    /// Notes that `connection` completed one of `peer_id`'s messages.
+    fn on_outbound_done(&mut self, peer_id: PeerId, connection: ConnectionId) {
+        if let Some((sending_on, in_flight)) = self.sending_on.get_mut(&peer_id) {
+            if *sending_on == connection {
+                *in_flight = in_flight.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Removes an outstanding message and frees its buffer slot.
+    fn untrack(&mut self, id: &MessageId) {
+        if let Some((peer_id, _)) = self.outstanding.remove(id) {
+            self.lock_buffers().release(&peer_id);
+        }
+    }
+
+    fn send_encoded(&mut self, peer_id: PeerId, data: Bytes, metadata: &Metadata) -> MessageId {
+        if !self.lock_buffers().try_reserve(peer_id) {
+            return self.send_failed(peer_id, SendError::BufferFull);
+        }
+        let id = self.next_message_id();
+        self.send_reserved(id, peer_id, data, metadata);
+        id
+    }
+
+    fn send_reserved(&mut self, id: MessageId, peer_id: PeerId, data: Bytes, metadata: &Metadata) {
+        self.track(id, peer_id);
+        let result =
+            self.encode_envelope(id, peer_id, metadata, data)
+                .and_then(|(envelope, data)| {
+                    let event = HandlerIn::Send { id, envelope, data };
+                    self.dispatch(peer_id, OutboundId::Message(id), event)
+                });
+        if let Err(error) = result {
+            self.fail(peer_id, OutboundId::Message(id), error);
+        }
+    }
+
+    /// Encodes the envelope of the message `id` to `peer_id`, returning it
+    /// with the payload to send.
+    ///
+    /// The payload is sealed to `peer_id` if requested by `metadata`, and the
+    /// envelope signed if [`Config::with_signing`] is set. The signature
+    /// covers the sealed payload, so forwarding peers can verify it.
+    fn encode_envelope(
+        &self,
+        id: MessageId,
+        peer_id: PeerId,
+        metadata: &Metadata,
+        data: Bytes,
+    ) -> std::result::Result<(Bytes, Bytes), SendError> {
+        let data = if metadata.is_sealed() {
+            sealed::seal(&peer_id, &data)
+                .map_err(SendError::Sealing)?
+                .into()
+        } else {
+            data
+        };
+        let envelope = envelope::encode(id, metadata);
+        let envelope = match self.config.keypair() {
+            Some(keypair) => {
+                envelope::sign(&envelope, &data, keypair).map_err(SendError::Signing)?
+            }
+            None => envelope,
+        };
+        Ok((envelope, data))
+    }
+
+    /// Decrypts and decodes a message received from `peer`, unless it
+    /// belongs to a file transfer.
+    fn on_received(
+        &mut self,
+        peer: PeerId,
+        msg: MsgContent,
+        version: Version,
+    ) -> Option<Event<C::Message>> {
+        let sealed = msg.envelope.as_ref().is_some_and(|e| e.sealed);
+        let data = if sealed {
+            let opened = match self.config.decryption_key() {
+                Some(keypair) => sealed::open(keypair, &msg.data),
+                None => Err(SealError::NO_KEY),
+            };
+            match opened {
+                Ok(data) => data,
+                Err(error) => {
+                    return Some(Event::InboundFailed(InboundError::Decryption {
+                        peer,
+                        error,
+                    }))
+                }
+            }
+        } else {
+            msg.data
+        };
+        if msg.envelope.as_ref().is_some_and(|e| e.file) {
+            self.files.on_message(peer, &data);
+            return None;
+        }
+        let event = match self.codec.decode(data) {
+            Ok(message) => Event::Received {
+                peer,
+                message,
+                version,
+                envelope: msg.envelope,
+                author: msg.author.unwrap_or(peer),
+                verified: msg.author.is_some(),
+            },
+            Err(e) => Event::InboundFailed(InboundError::Codec {
+                peer,
+                error: Box::new(e),
+            }),
+        };
+        Some(event)
+    }
+
+    /// Carries out a command submitted through a [`Control`].
+    fn on_command(&mut self, command: Command<C::Message>) {
+        let (id, peer_id, message, metadata) = match command {
+            Command::Send(id, peer_id, message, metadata) => (id, peer_id, message, metadata),
+            Command::OpenStream(peer_id, sender) => return self.on_open_stream(peer_id, sender),
+        };
+        match self.codec.encode(&message) {
+            Ok(data) => self.send_reserved(id, peer_id, data.into(), &metadata),
+            Err(e) => {
+                self.lock_buffers().release(&peer_id);
+                self.fail(
+                    peer_id,
+                    OutboundId::Message(id),
+                    SendError::Codec(Box::new(e)),
+                );
+            }
+        }
+    }
+
+    fn send_failed(&mut self, peer_id: PeerId, error: SendError) -> MessageId {
+        let id = self.next_message_id();
+        self.fail(peer_id, OutboundId::Message(id), error);
+        id
+    }
+
+    /// Sends a request to `peer_id`.
+    ///
+    /// The remote's answer is reported as [`Event::Response`], or
+    /// [`Event::RequestFailed`] if there is none.
+    pub fn request(&mut self, peer_id: PeerId, data: impl Into<C::Message>) -> RequestId {
+        let request_id = RequestId(self.next_request_id.fetch_add(1, Ordering::Relaxed));
+        self.outstanding_requests.insert(request_id, peer_id);
+        let result = match self.codec.encode(&data.into()) {
+            Ok(data) => {
+                let event = HandlerIn::Request {
+                    id: request_id,
+                    data,
+                };
+                self.dispatch(peer_id, OutboundId::Request(request_id), event)
+            }
+            Err(e) => Err(SendError::Codec(Box::new(e))),
+        };
+        if let Err(error) = result {
+            self.fail(peer_id, OutboundId::Request(request_id), error);
+        }
+        request_id
+    }
+
+    /// Answers the inbound request `channel` was handed out for.
+    ///
+    /// Returns the data back if the request is no longer waiting, e.g. because
+    /// it timed out or the connection closed, or if it can't be encoded.
+    pub fn respond(
+        &mut self,
+        channel: ResponseChannel,
+        data: impl Into<C::Message>,
+    ) -> std::result::Result<(), C::Message> {
+        let data = data.into();
+        if !channel.is_open() {
+            return Err(data);
+        }
+        match self.codec.encode(&data) {
+            Ok(bytes) => channel.sender.send(bytes).map_err(|_| data),
+            Err(e) => {
+                log::warn!("Failed to encode response: {}", e);
+                Err(data)
+            }
+        }
+    }
+
+    /// Hands `event` to a connection to `peer_id`, or queues it and dials the
+    /// peer if there is none.
+    fn dispatch(
+        &mut self,
+        peer_id: PeerId,
+        id: OutboundId,
+        event: HandlerIn,
+    ) -> std::result::Result<(), SendError> {
+        if self.connections.contains_key(&peer_id) {
+            self.notify_handler(peer_id, event);
+            return Ok(());
+        }
+        let capacity = self.config.pending_queue_size();
+        if capacity == 0 {
+            return Err(SendError::NotConnected);
+        }
+        let queue = self.pending.entry(peer_id).or_default();
+        if queue.len() >= capacity {
+            return Err(SendError::QueueFull);
+        }
+        let dial = queue.is_empty();
+        queue.push_back(PendingOutbound {
+            id,
+            event,
+            deadline: Instant::now() + self.config.pending_ttl(),
+        });
+        if dial {
+            let handler = self.new_handler();
+            self.events.push_back(NetworkBehaviourAction::Dial {
+                opts: DialOpts::peer_id(peer_id)
+                    .condition(PeerCondition::Disconnected)
+                    .build(),
+                handler,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reports the failure of an outstanding message or request.
+    fn fail(&mut self, peer: PeerId, id: OutboundId, error: SendError) {
+        let event = match id {
+            OutboundId::Message(id) => {
+                self.untrack(&id);
+                if self.files.owns(&id) {
+                    let connected = self.connections.contains_key(&peer);
+                    return self.files.on_send_failed(&id, error, connected);
+                }
+                Event::SendFailed { id, peer, error }
+            }
+            OutboundId::Request(request_id) => {
+                self.outstanding_requests.remove(&request_id);
+                Event::RequestFailed {
+                    peer,
+                    request_id,
+                    error,
+                }
+            }
+        };
+        self.events
+            .push_back(NetworkBehaviourAction::GenerateEvent(event));
+    }
+
+    /// Fails all queued messages and requests whose TTL has passed.
+    fn expire_pending(&mut self, now: Instant) {
+        let mut expired = Vec::new();
+        self.pending.retain(|peer, queue| {
+            while queue.front().is_some_and(|p| p.deadline <= now) {
+                let pending = queue.pop_front().expect("front exists");
+                expired.push((*peer, pending.id));
+            }
+            !queue.is_empty()
+        });
+        expired.sort_unstable_by_key(|(_, id)| *id);
+        for (peer, id) in expired {
+            self.fail(peer, id, SendError::Expired);
+        }
+    }
+
+    /// Hands `event` to the preferred connection to the connected peer `peer_id`.
+    fn notify_handler(&mut self, peer_id: PeerId, event: HandlerIn) {
+        let mut connection = preferred(&self.connections[&peer_id])
+            .expect("connected peers have a connection")
+            .id;
+        if self.config.sequential_outbound() && matches!(event, HandlerIn::Send { .. }) {
+            let (sending_on, in_flight) = self.sending_on.entry(peer_id).or_insert((connection, 0));
+            if *in_flight == 0 {
+                *sending_on = connection;
+            }
+            *in_flight += 1;
+            connection = *sending_on;
+        }
+        self.notify_connection(peer_id, connection, event);
+    }
+
+    /// Hands `event` to `connection`, noting it as the connection of the
+    /// message it sends, if any.
+    fn notify_connection(&mut self, peer_id: PeerId, connection: ConnectionId, event: HandlerIn) {
+        if let HandlerIn::Send { id, .. } = &event {
+            if let Some(outstanding) = self.outstanding.get_mut(id) {
+                outstanding.1 = Some(connection);
+            }
+        }
         self.events
             .push_back(NetworkBehaviourAction::NotifyHandler {
                 peer_id,
-                handler: NotifyHandler::Any,
-                event: protocol::MsgContent { data: data.into() },
+                handler: NotifyHandler::One(connection),
+                event,
             });
     }
 }
 
 impl Default for Behaviour {
     fn default() -> Self {
-        Self::new()
+        Self::new(Config::default())
     }
 }
 
-impl NetworkBehaviour for Behaviour {
+impl<C: Codec> NetworkBehaviour for Behaviour<C> {
     type ConnectionHandler = Handler;
-    type OutEvent = Event;
+    type OutEvent = Event<C::Message>;
 
     fn new_handler(&mut self) -> Self::ConnectionHandler {
-        Handler::new()
+        Handler::new(
+            self.config.clone(),
+            self.next_request_id.clone(),
+            self.counters.clone(),
+        )
     }
 
-    fn inject_event(&mut self, peer: PeerId, conn_id: ConnectionId, result: protocol::MsgContent) {
-        println!("PeerId {:?},ConnId {:?}", peer, conn_id);
-        self.events
-            .push_front(NetworkBehaviourAction::GenerateEvent(Event {
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        connection_id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+        _: Option<&Vec<Multiaddr>>,
+        _: usize,
+    ) {
+        let connections = self.connections.entry(*peer_id).or_default();
+        let was_relayed = preferred(connections).map(|c| c.relayed);
+        connections.push(Connection::new(*connection_id, endpoint.clone()));
+        if was_relayed == Some(true) && !preferred(connections).expect("just pushed").relayed {
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                Event::DirectConnectionPreferred {
+                    peer: *peer_id,
+                    connection: *connection_id,
+                },
+            ));
+        }
+        if self.pinned.contains(peer_id) {
+            self.events
+                .push_back(NetworkBehaviourAction::NotifyHandler {
+                    peer_id: *peer_id,
+                    handler: NotifyHandler::One(*connection_id),
+                    event: HandlerIn::Pin(true),
+                });
+        }
+        for pending in self.pending.remove(peer_id).into_iter().flatten() {
+            self.notify_handler(*peer_id, pending.event);
+        }
+        self.files.on_connected(*peer_id);
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        connection_id: &ConnectionId,
+        _: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        if let Some(connection) = self
+            .connections
+            .get_mut(peer_id)
+            .and_then(|cs| cs.iter_mut().find(|c| c.id == *connection_id))
+        {
+            *connection = Connection::new(*connection_id, new.clone());
+        }
+    }
+
+    fn inject_dial_failure(&mut self, peer_id: Option<PeerId>, _: Handler, error: &DialError) {
+        let peer_id = match peer_id {
+            Some(peer_id) => peer_id,
+            None => return,
+        };
+        if let DialError::DialPeerConditionFalse(_) = error {
+            // Already connected, the queue is flushed on `inject_connection_established`.
+            return;
+        }
+        if let Some(queue) = self.pending.remove(&peer_id) {
+            log::debug!("Failed to dial {}: {}", peer_id, error);
+            for pending in queue {
+                self.fail(peer_id, pending.id, SendError::DialFailed);
+            }
+        }
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        connection_id: &ConnectionId,
+        _: &ConnectedPoint,
+        mut handler: Handler,
+        remaining_established: usize,
+    ) {
+        if let Some(connections) = self.connections.get_mut(peer_id) {
+            connections.retain(|c| c.id != *connection_id);
+        }
+        if matches!(self.sending_on.get(peer_id), Some((c, _)) if c == connection_id) {
+            self.sending_on.remove(peer_id);
+        }
+        let mut failed: Vec<OutboundId> = handler.take_pending().collect();
+        // Events still queued for the connection never reach it.
+        let (stale, events): (VecDeque<_>, VecDeque<_>) =
+            mem::take(&mut self.events).into_iter().partition(|event| {
+                matches!(
+                    event,
+                    NetworkBehaviourAction::NotifyHandler {
+                        peer_id: p,
+                        handler: NotifyHandler::One(c),
+                        ..
+                    } if p == peer_id && c == connection_id
+                )
+            });
+        self.events = events;
+        for event in stale {
+            if let NetworkBehaviourAction::NotifyHandler { event, .. } = event {
+                match event {
+                    HandlerIn::Send { id, .. } => failed.push(OutboundId::Message(id)),
+                    HandlerIn::Request { id, .. } => failed.push(OutboundId::Request(id)),
+                    HandlerIn::OpenStream(sender) => {
+                        let _ = sender.send(Err(SendError::ConnectionClosed));
+                    }
+                    HandlerIn::Pin(_) => {}
+                }
+            }
+        }
+        failed.extend(
+            self.outstanding
+                .iter()
+                .filter(|(_, (_, c))| *c == Some(*connection_id))
+                .map(|(id, _)| OutboundId::Message(*id)),
+        );
+        if remaining_established == 0 {
+            self.connections.remove(peer_id);
+            self.files.on_disconnected(*peer_id);
+            // Messages and requests that never reached a handler are lost with the last connection.
+            failed.extend(
+                self.outstanding
+                    .iter()
+                    .filter(|(_, (peer, _))| peer == peer_id)
+                    .map(|(id, _)| OutboundId::Message(*id)),
+            );
+            failed.extend(
+                self.outstanding_requests
+                    .iter()
+                    .filter(|(_, peer)| *peer == peer_id)
+                    .map(|(id, _)| OutboundId::Request(*id)),
+            );
+        }
+        failed.sort_unstable();
+        failed.dedup();
+        for id in failed {
+            let outstanding = match id {
+                OutboundId::Message(id) => self.outstanding.contains_key(&id),
+                OutboundId::Request(id) => self.outstanding_requests.contains_key(&id),
+            };
+            if outstanding {
+                self.fail(*peer_id, id, SendError::ConnectionClosed);
+            }
+        }
+        let mut unanswered: Vec<RequestId> = handler.take_pending_responses().collect();
+        unanswered.sort_unstable();
+        for request_id in unanswered {
+            self.events.push_back(NetworkBehaviourAction::GenerateEvent(
+                Event::ResponseFailed {
+                    peer: *peer_id,
+                    request_id,
+                    error: ResponseError::ConnectionClosed,
+                },
+            ));
+        }
+    }
+
+    fn inject_event(&mut self, peer: PeerId, conn_id: ConnectionId, event: HandlerEvent) {
+        log::trace!("PeerId {:?},ConnId {:?}", peer, conn_id);
+        let event = match event {
+            HandlerEvent::Received(msg, version) => match self.on_received(peer, msg, version) {
+                Some(event) => event,
+                None => return,
+            },
+            HandlerEvent::SendSucceeded(id, acked) => {
+                self.on_outbound_done(peer, conn_id);
+                self.untrack(&id);
+                if self.files.owns(&id) {
+                    return self.files.on_sent(&id);
+                }
+                Event::SendSucceeded { id, peer, acked }
+            }
+            HandlerEvent::SendFailed(id, error) => {
+                self.on_outbound_done(peer, conn_id);
+                self.untrack(&id);
+                if self.files.owns(&id) {
+                    let connected = self.connections.contains_key(&peer);
+                    return self.files.on_send_failed(&id, error, connected);
+                }
+                Event::SendFailed { id, peer, error }
+            }
+            HandlerEvent::InboundFailed(ConnectionHandlerUpgrErr::Upgrade(
+                UpgradeError::Apply(protocol::RecvError::InvalidEnvelope(
+                    envelope::DecodeError::InvalidSignature(author),
+                )),
+            )) => Event::InvalidSignature { peer, author },
+            HandlerEvent::InboundFailed(error) => {
+                Event::InboundFailed(InboundError::new(peer, error))
+            }
+            HandlerEvent::Request { id, data, channel } => match self.codec.decode(data) {
+                Ok(data) => Event::Request {
+                    peer,
+                    request_id: id,
+                    data,
+                    channel,
+                },
+                Err(e) => Event::InboundFailed(InboundError::Codec {
+                    peer,
+                    error: Box::new(e),
+                }),
+            },
+            HandlerEvent::Response(request_id, data) => {
+                self.outstanding_requests.remove(&request_id);
+                match self.codec.decode(data) {
+                    Ok(data) => Event::Response {
+                        peer,
+                        request_id,
+                        data,
+                    },
+                    Err(e) => Event::RequestFailed {
+                        peer,
+                        request_id,
+                        error: SendError::Codec(Box::new(e)),
+                    },
+                }
+            }
+            HandlerEvent::RequestFailed(request_id, error) => {
+                self.outstanding_requests.remove(&request_id);
+                Event::RequestFailed {
+                    peer,
+                    request_id,
+                    error,
+                }
+            }
+            HandlerEvent::InboundStream(stream) => {
+                let listener = self.stream_listener.as_ref();
+                if listener.is_none_or(|l| l.unbounded_send((peer, stream)).is_err()) {
+                    log::debug!("Dropping stream from {}, nobody is listening", peer);
+                }
+                return;
+            }
+            HandlerEvent::ResponseSent(request_id) => Event::ResponseSent { peer, request_id },
+            HandlerEvent::ResponseFailed(request_id, error) => Event::ResponseFailed {
                 peer,
-                result,
-            }))
+                request_id,
+                error,
+            },
+        };
+        self.events
+            .push_back(NetworkBehaviourAction::GenerateEvent(event))
     }
 
     fn poll(
         &mut self,
-        _: &mut Context<'_>,
+        cx: &mut Context<'_>,
         _: &mut impl PollParameters,
     ) -> Poll<NetworkBehaviourAction<Self::OutEvent, Self::ConnectionHandler>> {
-        if let Some(e) = self.events.pop_back() {
+        while let Poll::Ready(Some(command)) = self.commands.poll_next_unpin(cx) {
+            self.on_command(command);
+        }
+
+        self.files.poll(cx);
+        self.send_file_messages();
+        while let Some(event) = self.files.next_event() {
+            self.events
+                .push_back(NetworkBehaviourAction::GenerateEvent(Event::File(event)));
+        }
+
+        loop {
+            if let Some(timer) = self.pending_timer.as_mut() {
+                if timer.poll_unpin(cx).is_pending() {
+                    break;
+                }
+                self.pending_timer = None;
+                self.expire_pending(Instant::now());
+            }
+            let next = self
+                .pending
+                .values()
+                .filter_map(|queue| queue.front())
+                .map(|pending| pending.deadline)
+                .min();
+            match next {
+                Some(deadline) => {
+                    let delay = deadline.saturating_duration_since(Instant::now());
+                    self.pending_timer = Some(Delay::new(delay));
+                }
+                None => break,
+            }
+        }
+
+        if let Some(e) = self.events.pop_front() {
             Poll::Ready(e)
         } else {
             Poll::Pending
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker_ref;
+    use libp2p::core::Endpoint;
+    use libp2p::swarm::AddressRecord;
+
+    struct Params(PeerId);
+
+    impl PollParameters for Params {
+        type SupportedProtocolsIter = std::iter::Empty<Vec<u8>>;
+        type ListenedAddressesIter = std::iter::Empty<Multiaddr>;
+        type ExternalAddressesIter = std::iter::Empty<AddressRecord>;
+
+        fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+            std::iter::empty()
+        }
+
+        fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+            std::iter::empty()
+        }
+
+        fn external_addresses(&self) -> Self::ExternalAddressesIter {
+            std::iter::empty()
+        }
+
+        fn local_peer_id(&self) -> &PeerId {
+            &self.0
+        }
+    }
+
+    fn poll(behaviour: &mut Behaviour) -> Option<NetworkBehaviourAction<Event, Handler>> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        match behaviour.poll(&mut cx, &mut Params(PeerId::random())) {
+            Poll::Ready(action) => Some(action),
+            Poll::Pending => None,
+        }
+    }
+
+    fn connect(behaviour: &mut Behaviour, peer_id: PeerId, connection: ConnectionId) {
+        connect_to(behaviour, peer_id, connection, "/memory/1");
+    }
+
+    fn connect_to(
+        behaviour: &mut Behaviour,
+        peer_id: PeerId,
+        connection: ConnectionId,
+        address: &str,
+    ) {
+        let endpoint = ConnectedPoint::Dialer {
+            address: address.parse().unwrap(),
+            role_override: Endpoint::Dialer,
+        };
+        behaviour.inject_connection_established(&peer_id, &connection, &endpoint, None, 0);
+    }
+
+    #[test]
+    fn hands_messages_to_handler_in_send_order() {
+        let mut behaviour = Behaviour::default();
+        let peer_id = PeerId::random();
+        connect(&mut behaviour, peer_id, ConnectionId::new(1));
+        let ids: Vec<_> = (0..5u8).map(|i| behaviour.send(vec![i], peer_id)).collect();
+        for id in ids {
+            match poll(&mut behaviour) {
+                Some(NetworkBehaviourAction::NotifyHandler {
+                    event: HandlerIn::Send { id: sent, .. },
+                    ..
+                }) => assert_eq!(sent, id),
+                _ => panic!("expected the message to be handed to the handler"),
+            }
+        }
+        assert!(poll(&mut behaviour).is_none());
+    }
+
+    #[test]
+    fn reports_events_in_order() {
+        let mut behaviour = Behaviour::default();
+        let peer_id = PeerId::random();
+        let connection = ConnectionId::new(1);
+        connect(&mut behaviour, peer_id, connection);
+        let ids: Vec<_> = (0..5u8).map(|i| behaviour.send(vec![i], peer_id)).collect();
+        while poll(&mut behaviour).is_some() {}
+        for id in &ids {
+            behaviour.inject_event(peer_id, connection, HandlerEvent::SendSucceeded(*id, true));
+        }
+        for id in ids {
+            match poll(&mut behaviour) {
+                Some(NetworkBehaviourAction::GenerateEvent(Event::SendSucceeded {
+                    id: succeeded,
+                    ..
+                })) => assert_eq!(succeeded, id),
+                _ => panic!("expected the message to succeed"),
+            }
+        }
+    }
+
+    fn sent_on(behaviour: &mut Behaviour) -> ConnectionId {
+        loop {
+            match poll(behaviour) {
+                Some(NetworkBehaviourAction::NotifyHandler {
+                    handler: NotifyHandler::One(connection),
+                    event: HandlerIn::Send { .. },
+                    ..
+                }) => return connection,
+                Some(_) => continue,
+                None => panic!("expected a message to be handed to a handler"),
+            }
+        }
+    }
+
+    #[test]
+    fn sequential_messages_stay_on_their_connection() {
+        let mut behaviour = Behaviour::new(Config::new().with_sequential_outbound(true));
+        let peer_id = PeerId::random();
+        let (relayed, direct) = (ConnectionId::new(1), ConnectionId::new(2));
+        connect_to(&mut behaviour, peer_id, relayed, "/memory/1/p2p-circuit");
+
+        let first = behaviour.send(vec![1], peer_id);
+        assert_eq!(sent_on(&mut behaviour), relayed);
+        connect(&mut behaviour, peer_id, direct);
+        let second = behaviour.send(vec![2], peer_id);
+        assert_eq!(sent_on(&mut behaviour), relayed);
+
+        for id in [first, second] {
+            behaviour.inject_event(peer_id, relayed, HandlerEvent::SendSucceeded(id, true));
+        }
+        behaviour.send(vec![3], peer_id);
+        assert_eq!(sent_on(&mut behaviour), direct);
+    }
+
+    #[test]
+    fn doesnt_dial_to_cancel_transfer() {
+        let mut behaviour = Behaviour::default();
+        let peer_id = PeerId::random();
+        let id: file::TransferId = "1".parse().unwrap();
+        let manifest = file::Manifest {
+            name: "file".to_owned(),
+            size: 0,
+            chunk_size: 1,
+            hash: [0; 32],
+            chunk_hashes: Vec::new(),
+        };
+        let offer = file::Message::Offer(id, manifest).encode();
+        behaviour.files.on_message(peer_id, &offer);
+        assert!(behaviour.cancel_transfer(id));
+        while let Some(action) = poll(&mut behaviour) {
+            assert!(!matches!(action, NetworkBehaviourAction::Dial { .. }));
+        }
+    }
+}
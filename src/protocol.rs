@@ -1,63 +1,560 @@
+use crate::envelope::{self, Envelope};
+use crate::handler::Counters;
+use crate::Compression;
+use bytes::Bytes;
 use futures::future::BoxFuture;
 use futures::prelude::*;
-use libp2p::core::{upgrade, InboundUpgrade, OutboundUpgrade, UpgradeInfo};
+use futures::stream::{self, BoxStream};
+use futures_timer::Delay;
+use libp2p::core::upgrade::ProtocolName;
+use libp2p::core::{upgrade, InboundUpgrade, OutboundUpgrade, PeerId, UpgradeInfo};
 use libp2p::swarm::NegotiatedSubstream;
-use std::{io, iter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{error, fmt, io};
 
+/// Wire versions of the message protocol, see
+/// [`Config::with_versions`](crate::Config::with_versions).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Version {
+    /// The payload is written and the substream closed.
+    V1_0,
+    /// Like 1.0.0, but the receiver answers every payload with an ack frame.
+    V1_1,
+    /// Like 1.1.0, but the payload is preceded by an [`Envelope`].
+    V2_0,
+}
+
+impl Version {
+    /// The version as it appears at the end of the protocol name.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::V1_0 => "1.0.0",
+            Version::V1_1 => "1.1.0",
+            Version::V2_0 => "2.0.0",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What a substream negotiated on a [`ProtocolId`] is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Message(Version),
+    /// The receiver answers the payload with a response frame.
+    Request,
+    /// The substream is handed to the application as is.
+    Stream,
+    /// A long-lived substream carrying many message frames.
+    Persistent,
+}
+
+/// A protocol name, e.g. `/p2p/msg/1.1.0`, and what it is used for.
+#[derive(Debug, Clone)]
+pub struct ProtocolId {
+    name: Arc<[u8]>,
+    kind: Kind,
+    /// The algorithm payloads may be compressed with.
+    compression: Option<Compression>,
+}
+
+impl ProtocolName for ProtocolId {
+    fn protocol_name(&self) -> &[u8] {
+        &self.name
+    }
+}
+
+/// All protocols a connection speaks, derived from the config.
+///
+/// Protocols with envelopes also exist as `<prefix>/<algorithm>/<version>`
+/// for every compression algorithm compiled in. All of them are accepted
+/// inbound, but only the configured one is proposed outbound.
+#[derive(Debug)]
+pub struct Protocols {
+    /// Message protocols in order of preference.
+    messages: Vec<ProtocolId>,
+    request: ProtocolId,
+    stream: ProtocolId,
+    /// Persistent substream protocols in order of preference.
+    persistent: Vec<ProtocolId>,
+    /// All protocols accepted inbound.
+    inbound: Vec<ProtocolId>,
+    /// Payloads smaller than this are sent uncompressed.
+    compression_threshold: usize,
+}
+
+impl Protocols {
+    pub fn new(
+        prefix: &str,
+        versions: &[Version],
+        compression: Option<(Compression, usize)>,
+    ) -> Self {
+        let id = |name: String, kind, compression| ProtocolId {
+            name: name.into_bytes().into(),
+            kind,
+            compression,
+        };
+        // `<prefix>/<algorithm>/<rest>` for each of `algorithms`, then `<prefix>/<rest>`.
+        let variants = |rest: &str, kind, algorithms: &[Compression]| {
+            algorithms
+                .iter()
+                .map(|c| id(format!("{}/{}/{}", prefix, c, rest), kind, Some(*c)))
+                .chain(Some(id(format!("{}/{}", prefix, rest), kind, None)))
+                .collect::<Vec<_>>()
+        };
+        let messages = |algorithms: &[Compression]| {
+            versions
+                .iter()
+                .flat_map(|v| match v {
+                    Version::V2_0 => variants(v.as_str(), Kind::Message(*v), algorithms),
+                    _ => variants(v.as_str(), Kind::Message(*v), &[]),
+                })
+                .collect::<Vec<_>>()
+        };
+        // Persistent substreams carry enveloped frames, so they come with 2.0.0.
+        let persistent = |algorithms: &[Compression]| {
+            if versions.contains(&Version::V2_0) {
+                variants("persistent/1.0.0", Kind::Persistent, algorithms)
+            } else {
+                Vec::new()
+            }
+        };
+        let configured: Vec<Compression> = compression.iter().map(|(c, _)| *c).collect();
+        let request = id(format!("{}/req/1.0.0", prefix), Kind::Request, None);
+        let stream = id(format!("{}/stream/1.0.0", prefix), Kind::Stream, None);
+        let mut inbound = messages(Compression::ALL);
+        inbound.push(request.clone());
+        inbound.push(stream.clone());
+        inbound.extend(persistent(Compression::ALL));
+        Protocols {
+            messages: messages(&configured),
+            request,
+            stream,
+            persistent: persistent(&configured),
+            inbound,
+            compression_threshold: compression.map_or(0, |(_, threshold)| threshold),
+        }
+    }
+
+    /// Whether persistent substreams are spoken at all.
+    pub fn has_persistent(&self) -> bool {
+        !self.persistent.is_empty()
+    }
+
+    /// How to compress payloads sent on a substream negotiated as `id`.
+    pub fn compression(&self, id: &ProtocolId) -> Option<(Compression, usize)> {
+        id.compression.map(|c| (c, self.compression_threshold))
+    }
+}
+
+/// Compresses `data` if `compression` is set, `data` has at least the
+/// threshold size and compressing actually makes it smaller, returning the
+/// envelope and payload to write.
+pub fn compress(
+    compression: Option<(Compression, usize)>,
+    envelope: Bytes,
+    data: Bytes,
+) -> [Bytes; 2] {
+    if let Some((compression, threshold)) = compression {
+        if data.len() >= threshold {
+            match compression.compress(&data) {
+                Ok(compressed) if compressed.len() < data.len() => {
+                    return [
+                        envelope::set_compression(&envelope, compression),
+                        compressed.into(),
+                    ];
+                }
+                Ok(_) => {}
+                Err(e) => log::debug!("Sending uncompressed, {} failed: {}", compression, e),
+            }
+        }
+    }
+    [envelope, data]
+}
+
+/// How long reading or writing a single frame may take.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub read: Duration,
+    pub write: Duration,
+}
+
+/// Tracks the inbound substreams of a connection that are being read, await
+/// a response or are open as raw or persistent streams, and refuses new ones
+/// beyond `max`.
+#[derive(Debug)]
+pub struct InboundSlots {
+    active: AtomicUsize,
+    max: usize,
+    counters: Arc<Counters>,
+}
+
+impl InboundSlots {
+    pub fn new(max: usize, counters: Arc<Counters>) -> Self {
+        InboundSlots {
+            active: AtomicUsize::new(0),
+            max,
+            counters,
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.active.load(Ordering::Relaxed) >= self.max
+    }
+
+    /// Takes a slot unless all are taken, counting the substream as refused
+    /// then.
+    fn try_acquire(self: &Arc<Self>) -> Option<Slot> {
+        let acquired = self
+            .active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                (active < self.max).then_some(active + 1)
+            });
+        if acquired.is_err() {
+            self.counters.refused.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.counters.inbound.fetch_add(1, Ordering::Relaxed);
+        Some(Slot(self.clone()))
+    }
+}
+
+/// An inbound substream counted in [`InboundSlots`] until dropped.
+pub struct Slot(Arc<InboundSlots>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::AcqRel);
+        self.0.counters.inbound.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Body of the ack frame written by the receiver on 1.1.0.
+const ACK: &[u8] = &[0x06];
+
+#[derive(Debug)]
 pub enum Success {
-    OK,
+    /// The remote read the payload and acknowledged it.
+    Acked,
+    /// The payload was written, but the negotiated protocol has no acks.
+    Sent,
+    /// The remote answered a request.
+    Response(Vec<u8>),
+    /// A raw stream was negotiated.
+    Stream(NegotiatedSubstream),
+    /// A persistent substream was negotiated, with the compression to apply
+    /// to its payloads.
+    Persistent(NegotiatedSubstream, Option<(Compression, usize)>),
+}
+
+/// Error reading an inbound message.
+#[derive(Debug)]
+pub enum RecvError {
+    /// The length prefix exceeds the configured maximum message size.
+    MessageTooLarge { announced_len: usize },
+    /// The remote didn't finish sending the message, or reading the ack, in time.
+    Timeout,
+    /// The message's envelope is malformed.
+    InvalidEnvelope(envelope::DecodeError),
+    /// I/O error while reading the message or writing the ack.
+    Io(io::Error),
+    /// The connection already had the maximum of inbound substreams when
+    /// this one was negotiated.
+    Refused,
+}
+
+impl RecvError {
+    fn into_io(self) -> io::Error {
+        match self {
+            RecvError::Io(e) => e,
+            RecvError::Timeout => timed_out(),
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for RecvError {
+    fn from(e: io::Error) -> Self {
+        RecvError::Io(e)
+    }
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::MessageTooLarge { announced_len } => {
+                write!(f, "message of {} bytes is too large", announced_len)
+            }
+            RecvError::Timeout => write!(f, "timeout"),
+            RecvError::InvalidEnvelope(e) => e.fmt(f),
+            RecvError::Io(e) => write!(f, "I/O error: {}", e),
+            RecvError::Refused => write!(f, "too many inbound substreams"),
+        }
+    }
+}
+
+impl error::Error for RecvError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RecvError::Io(e) => Some(e),
+            RecvError::InvalidEnvelope(e) => Some(e),
+            RecvError::MessageTooLarge { .. } | RecvError::Timeout | RecvError::Refused => None,
+        }
+    }
 }
 
 #[derive(Default, Debug, Clone)]
 pub struct MsgContent {
     pub data: Vec<u8>,
+    /// The envelope the data was received in, if the protocol has one.
+    pub envelope: Option<Envelope>,
+    /// The peer that signed the envelope, if it is signed.
+    pub author: Option<PeerId>,
+}
+
+impl MsgContent {
+    /// Splits a frame of a protocol with envelopes.
+    pub fn from_frame(frame: Vec<u8>, max_size: usize) -> Result<Self, RecvError> {
+        let decoded = envelope::decode(frame, max_size).map_err(RecvError::InvalidEnvelope)?;
+        Ok(MsgContent {
+            data: decoded.payload,
+            envelope: Some(decoded.envelope),
+            author: decoded.author,
+        })
+    }
+}
+
+/// Upgrade accepting inbound messages of at most `max_message_size` bytes.
+#[derive(Debug, Clone)]
+pub struct MsgInbound {
+    pub protocols: Arc<Protocols>,
+    pub max_message_size: usize,
+    pub timeouts: Timeouts,
+    pub slots: Arc<InboundSlots>,
 }
 
-impl UpgradeInfo for MsgContent {
-    type Info = &'static [u8];
-    type InfoIter = iter::Once<Self::Info>;
+/// What was read from an inbound substream.
+pub enum Inbound {
+    /// A message, already acknowledged if the protocol asks for it.
+    Message(MsgContent, Version),
+    /// A request; the response is to be written to the substream, which
+    /// occupies `Slot` until then.
+    Request(Vec<u8>, NegotiatedSubstream, Slot),
+    /// A raw stream opened by the remote, which occupies `Slot` until dropped.
+    Stream(NegotiatedSubstream, Slot),
+    /// A persistent substream the remote writes message frames to, which
+    /// occupies `Slot` until closed.
+    Persistent(NegotiatedSubstream, Slot),
+}
+
+/// Upgrade for an outbound substream.
+#[derive(Debug, Clone)]
+pub enum MsgOutbound {
+    /// Send a message.
+    Message {
+        /// Written before `data` if the negotiated version has envelopes.
+        envelope: Bytes,
+        /// Shared with the other recipients of a broadcast.
+        data: Bytes,
+        protocols: Arc<Protocols>,
+        timeouts: Timeouts,
+    },
+    /// Send a request and read a response of at most `max_response_size` bytes.
+    ///
+    /// Only writing the request is bounded by `timeouts`; the response may
+    /// take as long as the substream's timeout allows.
+    Request {
+        data: Vec<u8>,
+        max_response_size: usize,
+        protocols: Arc<Protocols>,
+        timeouts: Timeouts,
+    },
+    /// Negotiate a raw stream.
+    Stream { protocols: Arc<Protocols> },
+    /// Negotiate a persistent substream for message frames.
+    Persistent { protocols: Arc<Protocols> },
+}
+
+impl UpgradeInfo for MsgInbound {
+    type Info = ProtocolId;
+    type InfoIter = std::vec::IntoIter<Self::Info>;
 
     fn protocol_info(&self) -> Self::InfoIter {
-        iter::once(b"/p2p/msg/1.0.0")
+        if self.slots.is_full() {
+            // Offering no protocol makes the remote's negotiation fail.
+            self.slots.counters.refused.fetch_add(1, Ordering::Relaxed);
+            return Vec::new().into_iter();
+        }
+        self.protocols.inbound.clone().into_iter()
     }
 }
 
-impl InboundUpgrade<NegotiatedSubstream> for MsgContent {
-    type Output = Vec<u8>;
-    type Error = std::io::Error;
+impl InboundUpgrade<NegotiatedSubstream> for MsgInbound {
+    type Output = Inbound;
+    type Error = RecvError;
     type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
 
-    fn upgrade_inbound(self, socket: NegotiatedSubstream, _: Self::Info) -> Self::Future {
+    fn upgrade_inbound(self, mut socket: NegotiatedSubstream, info: Self::Info) -> Self::Future {
+        // Other substreams may have taken the last slot since offering the
+        // protocols.
+        let slot = match self.slots.try_acquire() {
+            Some(slot) => slot,
+            None => return future::err(RecvError::Refused).boxed(),
+        };
+        match info.kind {
+            Kind::Stream => return future::ok(Inbound::Stream(socket, slot)).boxed(),
+            Kind::Persistent => return future::ok(Inbound::Persistent(socket, slot)).boxed(),
+            Kind::Message(_) | Kind::Request => {}
+        }
         async move {
-            let packet = recv(socket).await?;
-            Ok(packet)
+            let packet = timeout(
+                self.timeouts.read,
+                recv(&mut socket, self.max_message_size),
+                || RecvError::Timeout,
+            )
+            .await?;
+            let version = match info.kind {
+                Kind::Request => return Ok(Inbound::Request(packet, socket, slot)),
+                Kind::Message(version) => version,
+                Kind::Stream | Kind::Persistent => {
+                    unreachable!("streams are returned before reading")
+                }
+            };
+            let content = match version {
+                Version::V2_0 => MsgContent::from_frame(packet, self.max_message_size)?,
+                Version::V1_0 | Version::V1_1 => MsgContent {
+                    data: packet,
+                    ..Default::default()
+                },
+            };
+            if version != Version::V1_0 {
+                timeout(
+                    self.timeouts.write,
+                    send_ack(&mut socket).map_err(RecvError::Io),
+                    || RecvError::Timeout,
+                )
+                .await?;
+            }
+            Ok(Inbound::Message(content, version))
         }
         .boxed()
     }
 }
 
-impl OutboundUpgrade<NegotiatedSubstream> for MsgContent {
+impl UpgradeInfo for MsgOutbound {
+    type Info = ProtocolId;
+    type InfoIter = std::vec::IntoIter<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        match self {
+            // Sealed and file payloads are meaningless without the envelope marking them.
+            MsgOutbound::Message {
+                protocols,
+                envelope,
+                ..
+            } if envelope::is_required(envelope) => protocols
+                .messages
+                .iter()
+                .filter(|id| id.kind == Kind::Message(Version::V2_0))
+                .cloned()
+                .collect(),
+            MsgOutbound::Message { protocols, .. } => protocols.messages.clone(),
+            MsgOutbound::Request { protocols, .. } => vec![protocols.request.clone()],
+            MsgOutbound::Stream { protocols } => vec![protocols.stream.clone()],
+            MsgOutbound::Persistent { protocols } => protocols.persistent.clone(),
+        }
+        .into_iter()
+    }
+}
+
+impl OutboundUpgrade<NegotiatedSubstream> for MsgOutbound {
     type Output = Success;
     type Error = std::io::Error;
     type Future = BoxFuture<'static, Result<Self::Output, Self::Error>>;
-    fn upgrade_outbound(self, socket: NegotiatedSubstream, _: Self::Info) -> Self::Future {
+    fn upgrade_outbound(self, mut socket: NegotiatedSubstream, info: Self::Info) -> Self::Future {
         async move {
-            send(socket, self.data).await?;
-            Ok(Success::OK)
+            match self {
+                MsgOutbound::Message {
+                    envelope,
+                    data,
+                    protocols,
+                    timeouts,
+                } => {
+                    let parts = match info.kind {
+                        Kind::Message(Version::V2_0) => {
+                            compress(protocols.compression(&info), envelope, data)
+                        }
+                        _ => [Bytes::new(), data],
+                    };
+                    let write = send_parts(&mut socket, parts);
+                    timeout(timeouts.write, write, timed_out).await?;
+                    if info.kind != Kind::Message(Version::V1_0) {
+                        timeout(timeouts.read, recv_ack(&mut socket), timed_out).await?;
+                        return Ok(Success::Acked);
+                    }
+                    Ok(Success::Sent)
+                }
+                MsgOutbound::Request {
+                    data,
+                    max_response_size,
+                    timeouts,
+                    ..
+                } => {
+                    timeout(timeouts.write, send(&mut socket, data), timed_out).await?;
+                    let response = recv(&mut socket, max_response_size)
+                        .await
+                        .map_err(RecvError::into_io)?;
+                    Ok(Success::Response(response))
+                }
+                MsgOutbound::Stream { .. } => Ok(Success::Stream(socket)),
+                MsgOutbound::Persistent { protocols } => {
+                    Ok(Success::Persistent(socket, protocols.compression(&info)))
+                }
+            }
         }
         .boxed()
     }
 }
 
-pub async fn recv<S>(mut socket: S) -> io::Result<Vec<u8>>
+/// Runs `f`, failing with `elapsed()` if it doesn't complete within `d`.
+pub async fn timeout<T, E>(
+    d: Duration,
+    f: impl Future<Output = Result<T, E>>,
+    elapsed: impl FnOnce() -> E,
+) -> Result<T, E> {
+    futures::pin_mut!(f);
+    match future::select(f, Delay::new(d)).await {
+        future::Either::Left((result, _)) => result,
+        future::Either::Right(((), _)) => Err(elapsed()),
+    }
+}
+
+/// The error reported when a write, or reading an ack or response, timed out.
+pub fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "timeout")
+}
+
+pub async fn recv<S>(mut socket: S, max_size: usize) -> Result<Vec<u8>, RecvError>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    let packet = upgrade::read_length_prefixed(&mut socket, usize::MAX).await?;
+    let len = upgrade::read_varint(&mut socket).await?;
+    if len > max_size {
+        return Err(RecvError::MessageTooLarge { announced_len: len });
+    }
+    let mut packet = vec![0; len];
+    socket.read_exact(&mut packet).await?;
 
     Ok(packet)
 }
-pub async fn send<S>(mut socket: S, data: Vec<u8>) -> io::Result<S>
+pub async fn send<S>(mut socket: S, data: impl AsRef<[u8]>) -> io::Result<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
@@ -65,3 +562,153 @@ where
     socket.close().await?;
     Ok(socket)
 }
+
+/// Writes the concatenation of `parts` as one frame and closes the substream.
+pub async fn send_parts<S>(mut socket: S, parts: [Bytes; 2]) -> io::Result<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write_frame(&mut socket, &parts).await?;
+    socket.close().await?;
+    Ok(socket)
+}
+
+/// Writes one frame of concatenated `parts` to a persistent substream,
+/// leaving it open.
+pub async fn send_frame<S>(mut socket: S, parts: [Bytes; 2]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    write_frame(&mut socket, &parts).await?;
+    socket.flush().await
+}
+
+async fn write_frame<S>(mut socket: S, parts: &[Bytes]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let len = parts.iter().map(Bytes::len).sum();
+    upgrade::write_varint(&mut socket, len).await?;
+    for part in parts {
+        socket.write_all(part).await?;
+    }
+    Ok(())
+}
+
+/// Reads the frames of a persistent substream until the remote closes it.
+///
+/// Waiting for the next frame is not bounded, but once its length was read
+/// the rest has to arrive within `read_timeout`. The stream ends after an error.
+pub fn recv_frames<S>(
+    socket: S,
+    max_size: usize,
+    read_timeout: Duration,
+) -> BoxStream<'static, Result<Vec<u8>, RecvError>>
+where
+    S: AsyncRead + Unpin + Send + 'static,
+{
+    stream::unfold(Some(socket), move |socket| async move {
+        let mut socket = socket?;
+        let len = match read_frame_len(&mut socket).await {
+            Ok(Some(len)) => len,
+            Ok(None) => return None,
+            Err(e) => return Some((Err(e.into()), None)),
+        };
+        if len > max_size {
+            return Some((Err(RecvError::MessageTooLarge { announced_len: len }), None));
+        }
+        let mut packet = vec![0; len];
+        let read = socket.read_exact(&mut packet).map_err(RecvError::Io);
+        match timeout(read_timeout, read, || RecvError::Timeout).await {
+            Ok(()) => Some((Ok(packet), Some(socket))),
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+    .boxed()
+}
+
+/// Reads the varint length prefix of a frame, or `None` if the substream
+/// ended before it.
+async fn read_frame_len<S>(socket: &mut S) -> io::Result<Option<usize>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut len = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let mut byte = [0u8];
+        if socket.read(&mut byte).await? == 0 {
+            if shift == 0 {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        len |= usize::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(len));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "overflow in variable-length integer",
+    ))
+}
+
+pub async fn send_ack<S>(mut socket: S) -> io::Result<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    upgrade::write_length_prefixed(&mut socket, ACK).await?;
+    socket.close().await?;
+    Ok(socket)
+}
+
+pub async fn recv_ack<S>(mut socket: S) -> io::Result<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ack = upgrade::read_length_prefixed(&mut socket, ACK.len()).await?;
+    if ack != ACK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid ack frame",
+        ));
+    }
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(protocols: &[ProtocolId]) -> Vec<String> {
+        protocols
+            .iter()
+            .map(|p| String::from_utf8_lossy(p.protocol_name()).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn persistent_protocols_follow_versions() {
+        let protocols = Protocols::new("/msg", &[Version::V2_0], None);
+        assert_eq!(names(&protocols.persistent), ["/msg/persistent/1.0.0"]);
+        assert!(names(&protocols.inbound).contains(&"/msg/persistent/1.0.0".to_owned()));
+
+        let protocols = Protocols::new("/msg", &[Version::V1_1], None);
+        assert!(!protocols.has_persistent());
+        assert!(!names(&protocols.inbound)
+            .iter()
+            .any(|name| name.contains("persistent")));
+    }
+
+    #[test]
+    fn inbound_slots_are_limited() {
+        let counters = Arc::new(Counters::default());
+        let slots = Arc::new(InboundSlots::new(1, counters.clone()));
+        let slot = slots.try_acquire().unwrap();
+        assert!(slots.is_full());
+        assert!(slots.try_acquire().is_none());
+        assert_eq!(counters.refused.load(Ordering::Relaxed), 1);
+        drop(slot);
+        assert!(slots.try_acquire().is_some());
+    }
+}
@@ -0,0 +1,147 @@
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use libp2p::core::identity::{Keypair, PublicKey};
+use libp2p::core::PeerId;
+use sha2::{Digest, Sha256, Sha512};
+use std::{error, fmt};
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// Prefix of the key derivation input, so the shared secret can't be
+/// mistaken for one derived for another purpose.
+const KEY_DOMAIN: &[u8] = b"libp2p-msg-sealed:";
+
+/// Length of the ephemeral public key preceding the ciphertext.
+const EPHEMERAL_LEN: usize = 32;
+
+/// Encrypts `plaintext` so only `recipient` can decrypt it.
+///
+/// The X25519 key of the recipient is derived from the Ed25519 key inlined
+/// in its peer ID. The sealed box is an ephemeral X25519 public key followed
+/// by the ChaCha20-Poly1305 ciphertext.
+pub fn seal(recipient: &PeerId, plaintext: &[u8]) -> Result<Vec<u8>, SealError> {
+    let public = x25519_public(recipient)?;
+    let ephemeral_secret: [u8; 32] = rand::random();
+    let ephemeral = x25519(ephemeral_secret, X25519_BASEPOINT_BYTES);
+    let cipher = cipher(x25519(ephemeral_secret, public), &ephemeral, &public)?;
+    let mut sealed = ephemeral.to_vec();
+    // The zero nonce is safe: the key is derived from an ephemeral key
+    // generated for this message alone, so no key encrypts twice.
+    sealed.extend(
+        cipher
+            .encrypt(&Nonce::default(), plaintext)
+            .map_err(|_| SealError("encryption failed"))?,
+    );
+    Ok(sealed)
+}
+
+/// Decrypts a sealed box addressed to the owner of `keypair`.
+pub fn open(keypair: &Keypair, sealed: &[u8]) -> Result<Vec<u8>, SealError> {
+    let keypair = match keypair {
+        Keypair::Ed25519(keypair) => keypair,
+        _ => return Err(SealError("local key is not Ed25519")),
+    };
+    if sealed.len() < EPHEMERAL_LEN {
+        return Err(SealError("truncated"));
+    }
+    let (ephemeral, ciphertext) = sealed.split_at(EPHEMERAL_LEN);
+    let ephemeral: [u8; 32] = ephemeral.try_into().expect("split at 32 bytes");
+    // The X25519 secret of an Ed25519 key is the scalar it signs with.
+    let mut secret = [0; 32];
+    secret.copy_from_slice(&Sha512::digest(keypair.secret())[..32]);
+    let public = x25519(secret, X25519_BASEPOINT_BYTES);
+    let cipher = cipher(x25519(secret, ephemeral), &ephemeral, &public)?;
+    cipher
+        .decrypt(&Nonce::default(), ciphertext)
+        .map_err(|_| SealError("decryption failed"))
+}
+
+/// The X25519 public key of `peer_id`, if its identity key is an inlined
+/// Ed25519 key.
+fn x25519_public(peer_id: &PeerId) -> Result<[u8; 32], SealError> {
+    let multihash = peer_id.as_ref();
+    if multihash.code() != 0 {
+        return Err(SealError("peer ID doesn't contain its public key"));
+    }
+    match PublicKey::from_protobuf_encoding(multihash.digest()) {
+        Ok(PublicKey::Ed25519(key)) => CompressedEdwardsY(key.encode())
+            .decompress()
+            .map(|point| point.to_montgomery().to_bytes())
+            .ok_or(SealError("invalid Ed25519 key")),
+        Ok(_) => Err(SealError("peer's key is not Ed25519")),
+        Err(_) => Err(SealError("invalid public key")),
+    }
+}
+
+/// The cipher keyed with the hash of the shared secret and both public keys.
+fn cipher(
+    shared: [u8; 32],
+    ephemeral: &[u8; 32],
+    recipient: &[u8; 32],
+) -> Result<ChaCha20Poly1305, SealError> {
+    if shared == [0; 32] {
+        return Err(SealError("low order key"));
+    }
+    let key = Sha256::new()
+        .chain_update(KEY_DOMAIN)
+        .chain_update(shared)
+        .chain_update(ephemeral)
+        .chain_update(recipient)
+        .finalize();
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+/// A payload couldn't be sealed to its recipient or opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SealError(&'static str);
+
+impl SealError {
+    pub(crate) const NO_KEY: SealError = SealError("no decryption key configured");
+}
+
+impl fmt::Display for SealError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sealed box: {}", self.0)
+    }
+}
+
+impl error::Error for SealError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let keypair = Keypair::generate_ed25519();
+        let sealed = seal(&keypair.public().to_peer_id(), b"secret").unwrap();
+        assert_eq!(open(&keypair, &sealed).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn fresh_ephemeral_key_per_message() {
+        let peer_id = Keypair::generate_ed25519().public().to_peer_id();
+        let first = seal(&peer_id, b"secret").unwrap();
+        let second = seal(&peer_id, b"secret").unwrap();
+        assert_ne!(first[..EPHEMERAL_LEN], second[..EPHEMERAL_LEN]);
+        assert_ne!(first[EPHEMERAL_LEN..], second[EPHEMERAL_LEN..]);
+    }
+
+    #[test]
+    fn wrong_recipient_fails() {
+        let recipient = Keypair::generate_ed25519().public().to_peer_id();
+        let sealed = seal(&recipient, b"secret").unwrap();
+        assert_eq!(
+            open(&Keypair::generate_ed25519(), &sealed),
+            Err(SealError("decryption failed"))
+        );
+    }
+
+    #[test]
+    fn tampered_ciphertext_fails() {
+        let keypair = Keypair::generate_ed25519();
+        let mut sealed = seal(&keypair.public().to_peer_id(), b"secret").unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        assert_eq!(open(&keypair, &sealed), Err(SealError("decryption failed")));
+    }
+}
@@ -0,0 +1,95 @@
+use crate::protocol::Slot;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use libp2p::core::PeerId;
+use libp2p::swarm::NegotiatedSubstream;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::{fmt, io::IoSlice};
+
+/// A raw substream negotiated on `<prefix>/stream/1.0.0`, see
+/// [`Behaviour::open_stream`](crate::Behaviour::open_stream).
+///
+/// The connection is kept alive while the stream exists.
+pub struct RawStream {
+    inner: NegotiatedSubstream,
+    /// Counts the open streams of the connection.
+    _guard: Arc<()>,
+    /// The inbound slot an inbound stream occupies.
+    _slot: Option<Slot>,
+}
+
+impl RawStream {
+    pub(crate) fn new(inner: NegotiatedSubstream, guard: Arc<()>, slot: Option<Slot>) -> Self {
+        RawStream {
+            inner,
+            _guard: guard,
+            _slot: slot,
+        }
+    }
+}
+
+impl fmt::Debug for RawStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawStream").finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for RawStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RawStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// The raw streams opened by remote peers, see
+/// [`Behaviour::incoming_streams`](crate::Behaviour::incoming_streams).
+#[derive(Debug)]
+pub struct IncomingStreams {
+    receiver: mpsc::UnboundedReceiver<(PeerId, RawStream)>,
+}
+
+impl IncomingStreams {
+    pub(crate) fn new(receiver: mpsc::UnboundedReceiver<(PeerId, RawStream)>) -> Self {
+        IncomingStreams { receiver }
+    }
+}
+
+impl Stream for IncomingStreams {
+    type Item = (PeerId, RawStream);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
@@ -0,0 +1,27 @@
+use libp2p::core::{transport::MemoryTransport, upgrade, Transport};
+use libp2p::swarm::Swarm;
+use libp2p::{identity, noise, yamux, Multiaddr, PeerId};
+use libp2p_msg::{Behaviour, Config};
+
+pub fn swarm(config: Config) -> Swarm<Behaviour> {
+    let key = identity::Keypair::generate_ed25519();
+    let peer_id = PeerId::from(key.public());
+    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
+        .into_authentic(&key)
+        .unwrap();
+    let transport = MemoryTransport::default()
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(yamux::YamuxConfig::default())
+        .boxed();
+    Swarm::new(transport, Behaviour::new(config), peer_id)
+}
+
+/// Makes `dialer` dial `listener` on a new memory address.
+pub fn connect(dialer: &mut Swarm<Behaviour>, listener: &mut Swarm<Behaviour>) {
+    let addr: Multiaddr = format!("/memory/{}", rand::random::<u64>())
+        .parse()
+        .unwrap();
+    listener.listen_on(addr.clone()).unwrap();
+    dialer.dial(addr).unwrap();
+}
@@ -0,0 +1,84 @@
+use futures::task::noop_waker_ref;
+use libp2p::core::connection::ConnectionId;
+use libp2p::core::{ConnectedPoint, Endpoint};
+use libp2p::swarm::{
+    AddressRecord, NetworkBehaviour, NetworkBehaviourAction, NotifyHandler, PollParameters,
+};
+use libp2p::{Multiaddr, PeerId};
+use libp2p_msg::{Behaviour, Config, Event, SendError};
+use std::task::{Context, Poll};
+
+struct Params(PeerId);
+
+impl PollParameters for Params {
+    type SupportedProtocolsIter = std::iter::Empty<Vec<u8>>;
+    type ListenedAddressesIter = std::iter::Empty<Multiaddr>;
+    type ExternalAddressesIter = std::iter::Empty<AddressRecord>;
+
+    fn supported_protocols(&self) -> Self::SupportedProtocolsIter {
+        std::iter::empty()
+    }
+
+    fn listened_addresses(&self) -> Self::ListenedAddressesIter {
+        std::iter::empty()
+    }
+
+    fn external_addresses(&self) -> Self::ExternalAddressesIter {
+        std::iter::empty()
+    }
+
+    fn local_peer_id(&self) -> &PeerId {
+        &self.0
+    }
+}
+
+fn endpoint() -> ConnectedPoint {
+    ConnectedPoint::Dialer {
+        address: "/memory/1".parse().unwrap(),
+        role_override: Endpoint::Dialer,
+    }
+}
+
+#[test]
+fn fails_messages_queued_for_closed_connection() {
+    let mut behaviour = Behaviour::new(Config::new().with_outbound_buffer(2));
+    let peer_id = PeerId::random();
+    let (closed, open) = (ConnectionId::new(1), ConnectionId::new(2));
+    for connection in [closed, open] {
+        behaviour.inject_connection_established(&peer_id, &connection, &endpoint(), None, 0);
+    }
+    let ids = [
+        behaviour.send(vec![1], peer_id),
+        behaviour.send_on(peer_id, closed, vec![2]),
+    ];
+    let handler = behaviour.new_handler();
+    behaviour.inject_connection_closed(&peer_id, &closed, &endpoint(), handler, 1);
+
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut failed = Vec::new();
+    while let Poll::Ready(action) = behaviour.poll(&mut cx, &mut Params(PeerId::random())) {
+        match action {
+            NetworkBehaviourAction::NotifyHandler {
+                handler: NotifyHandler::One(connection),
+                ..
+            } => assert_ne!(connection, closed),
+            NetworkBehaviourAction::GenerateEvent(Event::SendFailed {
+                id,
+                error: SendError::ConnectionClosed,
+                ..
+            }) => failed.push(id),
+            _ => {}
+        }
+    }
+    assert_eq!(failed, ids);
+
+    // Their buffer slots are free again.
+    behaviour.send(vec![3], peer_id);
+    behaviour.send(vec![4], peer_id);
+    while let Poll::Ready(action) = behaviour.poll(&mut cx, &mut Params(PeerId::random())) {
+        assert!(!matches!(
+            action,
+            NetworkBehaviourAction::GenerateEvent(Event::SendFailed { .. })
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+mod common;
+
+use futures::prelude::*;
+use libp2p::swarm::SwarmEvent;
+use libp2p_msg::file::{AutoAccept, Event as FileEvent};
+use libp2p_msg::{Config, Event};
+use std::fs;
+use std::path::PathBuf;
+
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("libp2p-msg-{:016x}", rand::random::<u64>()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Sends a file from a swarm with `config`, checking that it arrives
+/// intact.
+async fn send_file(config: Config) {
+    let dir = temp_dir();
+    let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    let source = dir.join("source.bin");
+    fs::write(&source, &data).unwrap();
+    let downloads = dir.join("downloads");
+    fs::create_dir_all(&downloads).unwrap();
+
+    let mut sender = common::swarm(config);
+    let mut receiver = common::swarm(Config::new().with_auto_accept(AutoAccept::new(&downloads)));
+    common::connect(&mut sender, &mut receiver);
+    let receiver_id = *receiver.local_peer_id();
+
+    let (mut sent, mut received) = (None, None);
+    while sent.is_none() || received.is_none() {
+        futures::select! {
+            event = sender.select_next_some() => match event {
+                SwarmEvent::ConnectionEstablished { .. } => {
+                    sender.behaviour_mut().send_file(receiver_id, &source).unwrap();
+                }
+                SwarmEvent::Behaviour(Event::File(event)) => match event {
+                    FileEvent::TransferCompleted { .. } => sent = Some(event),
+                    FileEvent::TransferFailed { reason, .. } => panic!("sending failed: {}", reason),
+                    _ => {}
+                },
+                _ => {}
+            },
+            event = receiver.select_next_some() => {
+                if let SwarmEvent::Behaviour(Event::File(event)) = event {
+                    match event {
+                        FileEvent::TransferCompleted { .. } => received = Some(event),
+                        FileEvent::TransferFailed { reason, .. } => {
+                            panic!("receiving failed: {}", reason)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    match sent.unwrap() {
+        FileEvent::TransferCompleted { path, verified, .. } => {
+            assert!(verified);
+            assert_eq!(path, source);
+        }
+        _ => unreachable!(),
+    }
+    match received.unwrap() {
+        FileEvent::TransferCompleted { path, verified, .. } => {
+            assert!(verified);
+            assert_eq!(path, downloads.join("source.bin"));
+            assert_eq!(fs::read(path).unwrap(), data);
+        }
+        _ => unreachable!(),
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[async_std::test]
+async fn sends_file_in_chunks() {
+    send_file(Config::new()).await;
+}
+
+#[async_std::test]
+async fn waits_for_room_in_outbound_buffer() {
+    send_file(Config::new().with_outbound_buffer(2)).await;
+}
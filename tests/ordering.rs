@@ -0,0 +1,36 @@
+mod common;
+
+use futures::prelude::*;
+use libp2p::swarm::SwarmEvent;
+use libp2p_msg::{Config, Event, Version};
+
+#[async_std::test]
+async fn sequential_outbound_preserves_send_order() {
+    const N: u32 = 50;
+    let config = Config::new()
+        .with_versions([Version::V1_1])
+        .with_sequential_outbound(true);
+    let mut sender = common::swarm(config.clone());
+    let mut receiver = common::swarm(config);
+    common::connect(&mut sender, &mut receiver);
+    let receiver_id = *receiver.local_peer_id();
+
+    let mut received = Vec::new();
+    while received.len() < N as usize {
+        futures::select! {
+            event = sender.select_next_some() => {
+                if let SwarmEvent::ConnectionEstablished { .. } = event {
+                    for i in 0..N {
+                        sender.behaviour_mut().send(i.to_be_bytes().to_vec(), receiver_id);
+                    }
+                }
+            }
+            event = receiver.select_next_some() => {
+                if let SwarmEvent::Behaviour(Event::Received { message, .. }) = event {
+                    received.push(u32::from_be_bytes(message.try_into().unwrap()));
+                }
+            }
+        }
+    }
+    assert_eq!(received, (0..N).collect::<Vec<_>>());
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use futures::prelude::*;
+use libp2p::swarm::SwarmEvent;
+use libp2p_msg::{Config, Event, Version};
+
+#[async_std::test]
+async fn persistent_stream_reports_version_and_unacked_sends() {
+    const N: usize = 5;
+    let config = Config::new().with_persistent_stream(true);
+    let mut sender = common::swarm(config.clone());
+    let mut receiver = common::swarm(config);
+    common::connect(&mut sender, &mut receiver);
+    let receiver_id = *receiver.local_peer_id();
+
+    let (mut received, mut succeeded) = (0, 0);
+    while received < N || succeeded < N {
+        futures::select! {
+            event = sender.select_next_some() => match event {
+                SwarmEvent::ConnectionEstablished { .. } => {
+                    for i in 0..N as u8 {
+                        sender.behaviour_mut().send(vec![i], receiver_id);
+                    }
+                }
+                SwarmEvent::Behaviour(Event::SendSucceeded { acked, .. }) => {
+                    assert!(!acked);
+                    succeeded += 1;
+                }
+                _ => {}
+            },
+            event = receiver.select_next_some() => {
+                if let SwarmEvent::Behaviour(Event::Received { version, envelope, .. }) = event {
+                    assert_eq!(version, Version::V2_0);
+                    assert!(envelope.is_some());
+                    received += 1;
+                }
+            }
+        }
+    }
+    assert_eq!(receiver.behaviour().stats().inbound_streams, 1);
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use futures::prelude::*;
+use libp2p::swarm::SwarmEvent;
+use libp2p_msg::{Config, SendError};
+
+#[async_std::test]
+async fn inbound_streams_count_against_the_limit() {
+    let mut opener = common::swarm(Config::new());
+    let mut acceptor = common::swarm(Config::new().with_max_inbound_streams(1));
+    let mut incoming = acceptor.behaviour_mut().incoming_streams().fuse();
+    common::connect(&mut opener, &mut acceptor);
+    let acceptor_id = *acceptor.local_peer_id();
+
+    loop {
+        futures::select! {
+            event = opener.select_next_some() => {
+                if let SwarmEvent::ConnectionEstablished { .. } = event {
+                    break;
+                }
+            }
+            _ = acceptor.select_next_some() => {}
+        }
+    }
+
+    let mut results = Vec::new();
+    let mut accepted = Vec::new();
+    for _ in 0..2 {
+        let mut open = opener.behaviour_mut().open_stream(acceptor_id).fuse();
+        loop {
+            futures::select! {
+                result = open => {
+                    results.push(result);
+                    break;
+                }
+                _ = opener.select_next_some() => {}
+                _ = acceptor.select_next_some() => {}
+                stream = incoming.select_next_some() => accepted.push(stream),
+            }
+        }
+    }
+
+    assert!(results[0].is_ok());
+    assert!(matches!(results[1], Err(SendError::UnsupportedProtocols)));
+    assert_eq!(acceptor.behaviour().stats().inbound_streams, 1);
+    assert_eq!(acceptor.behaviour().stats().refused_inbound, 1);
+}